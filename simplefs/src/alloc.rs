@@ -1,5 +1,8 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+
 use crate::fs::BLOCK_SIZE;
-use zerocopy::{AsBytes, FromBytes};
+use zerocopy::AsBytes;
 
 #[derive(Debug, PartialEq)]
 pub enum State {
@@ -7,33 +10,62 @@ pub enum State {
     Used,
 }
 
-#[repr(C)]
-#[derive(AsBytes, FromBytes, Clone, Copy)]
+/// The number of bits, and so tracked objects, held by each on-disk block of a bitmap.
+pub const BITS_PER_BLOCK: usize = BLOCK_SIZE * 8;
+const WORDS_PER_BLOCK: usize = BLOCK_SIZE / 8;
+
+/// An allocation bitmap spanning one or more disk blocks. Each 4K block tracks up to 4096 * 8
+/// objects for a total of 32,768 blocks (or inodes) per bitmap block.
+///
+/// Blocks of the bitmap are loaded and flushed individually, changes are tracked per block so only
+/// the blocks holding modified bits need to be written back.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Bitmap {
-    /// Stores 4096 bits mapping each bit to a logical block on disk. A 4K bitmap
-    /// supports tracking up to 4096 * 8 logical blocks for a total of 32,768 blocks
-    /// per bitmap block.
-    bitmap: [u64; BLOCK_SIZE / 8],
+    bitmap: Vec<u64>,
+    /// Blocks of the bitmap changed since the last call to `take_dirty_blocks`.
+    dirty_blocks: BTreeSet<u32>,
 }
 
 impl Bitmap {
-    pub fn new() -> Self {
+    /// Creates an empty bitmap spanning `blocks` disk blocks. Every block starts out dirty as none
+    /// of them have been written to disk yet.
+    pub fn new(blocks: u32) -> Self {
         Self {
-            bitmap: [0; BLOCK_SIZE / 8],
+            bitmap: vec![0; blocks as usize * WORDS_PER_BLOCK],
+            dirty_blocks: (0..blocks).collect(),
         }
     }
 
-    pub fn parse(buf: &[u8]) -> Self {
-        let map: *const Bitmap = buf.as_ptr() as *const Bitmap;
-        unsafe { *map }
+    /// The number of objects the bitmap can track.
+    pub fn capacity(&self) -> usize {
+        self.bitmap.len() * 64
     }
 
-    pub fn serialize(&self) -> &[u8] {
-        self.as_bytes()
+    /// Replaces a single block of the bitmap with the contents read from disk, the block is clean
+    /// until modified again.
+    pub fn load_block(&mut self, block: u32, buf: &[u8]) {
+        let start = block as usize * WORDS_PER_BLOCK;
+        self.bitmap[start..start + WORDS_PER_BLOCK]
+            .as_bytes_mut()
+            .copy_from_slice(&buf[..BLOCK_SIZE]);
+        self.dirty_blocks.remove(&block);
+    }
+
+    /// Serializes a single block of the bitmap for writing to disk.
+    pub fn serialize_block(&self, block: u32) -> &[u8] {
+        let start = block as usize * WORDS_PER_BLOCK;
+        self.bitmap[start..start + WORDS_PER_BLOCK].as_bytes()
+    }
+
+    /// Returns the blocks of the bitmap changed since the last call, clearing them.
+    pub fn take_dirty_blocks(&mut self) -> Vec<u32> {
+        let blocks = self.dirty_blocks.iter().copied().collect();
+        self.dirty_blocks.clear();
+        blocks
     }
 
     pub fn get(&self, blocknr: usize) -> State {
-        assert!(blocknr < (4096 * 8 - 1));
+        assert!(blocknr < self.capacity());
         // Grab of the u64 containing the significant bit.
         let outer_offset = self.bitmap[blocknr / 64];
 
@@ -48,24 +80,30 @@ impl Bitmap {
     }
 
     pub fn set_reserved(&mut self, blocknr: usize) {
-        assert!(blocknr < (4096 * 8 - 1));
+        assert!(blocknr < self.capacity());
         // Grab of the u64 containing the significant bit.
         let outer_offset = self.bitmap[blocknr / 64];
 
         let inner_offset = blocknr % 64;
         let mask = 0b01_u64 << inner_offset;
         self.bitmap[blocknr / 64] = outer_offset | mask;
+        self.dirty_blocks.insert((blocknr / BITS_PER_BLOCK) as u32);
+    }
+
+    /// Counts the free entries within `range`.
+    pub fn count_free(&self, range: Range<usize>) -> usize {
+        range.filter(|&i| self.get(i) == State::Free).count()
     }
 
-    #[allow(dead_code)]
     pub fn set_free(&mut self, blocknr: usize) {
-        assert!(blocknr < (4096 * 8 - 1));
+        assert!(blocknr < self.capacity());
         // Grab of the u64 containing the significant bit.
         let outer_offset = self.bitmap[blocknr / 64];
 
         let inner_offset = blocknr % 64;
-        let mask = 0b00_u64 << inner_offset;
+        let mask = !(0b01_u64 << inner_offset);
         self.bitmap[blocknr / 64] = outer_offset & mask;
+        self.dirty_blocks.insert((blocknr / BITS_PER_BLOCK) as u32);
     }
 }
 
@@ -76,37 +114,45 @@ impl Bitmap {
 /// ## Other Pre-Allocation Policies
 ///
 /// 1. Allocation that attempts to find enough contiguous available blocks so data can be allocated
-///    close together (speed ups through sequential reads).
+///    close together (speed ups through sequential reads), see `ContiguousAllocation`.
 /// 2. Allocation that attempts to spread randomly over blocks to prevent wear of physical devices
 ///    in the front section (that may be rewritten many times before allocating to the back).
-pub struct NextAvailableAllocation {
+pub struct NextAvailableAllocation<'a> {
     /// Keeps track of the next starting place for looking for available blocks.
     marker: usize,
     /// A simple bitmap tracking which blocks are allocated and which are free.
-    bitmap: Bitmap,
+    bitmap: &'a Bitmap,
     /// The maximum allocatable value available in hardware. For example, if you have 80 inode blocks
     /// available on disk, this value would be 80.
     cap: usize,
 }
 
-impl NextAvailableAllocation {
-    pub fn new(bitmap: Bitmap, cap: Option<usize>) -> Self {
-        let cap = cap.unwrap_or_else(|| BLOCK_SIZE / 8);
+impl<'a> NextAvailableAllocation<'a> {
+    /// Searches the bitmap for free entries below `cap`, defaulting to the capacity of the bitmap.
+    pub fn new(bitmap: &'a Bitmap, cap: Option<usize>) -> Self {
+        let cap = cap.unwrap_or_else(|| bitmap.capacity());
         Self {
             marker: 0,
             bitmap,
             cap,
         }
     }
+
+    /// Starts the search at `start` instead of the first entry, for callers that know every entry
+    /// before it is in use.
+    pub fn starting_at(mut self, start: usize) -> Self {
+        self.marker = start;
+        self
+    }
 }
 
-impl Iterator for NextAvailableAllocation {
+impl<'a> Iterator for NextAvailableAllocation<'a> {
     type Item = usize;
 
     fn next(&mut self) -> Option<Self::Item> {
         for i in self.marker..self.cap {
             if let State::Free = self.bitmap.get(i) {
-                self.marker += 1;
+                self.marker = i + 1;
                 return Some(i);
             }
         }
@@ -114,13 +160,54 @@ impl Iterator for NextAvailableAllocation {
     }
 }
 
+/// Finds runs of consecutive free entries so a file's data can be laid out contiguously on disk,
+/// letting a single extent describe many blocks and sequential reads hit neighbouring blocks.
+pub struct ContiguousAllocation<'a> {
+    bitmap: &'a Bitmap,
+    /// The maximum allocatable value, see `NextAvailableAllocation`.
+    cap: usize,
+}
+
+impl<'a> ContiguousAllocation<'a> {
+    /// Searches the bitmap for free runs below `cap`, defaulting to the capacity of the bitmap.
+    pub fn new(bitmap: &'a Bitmap, cap: Option<usize>) -> Self {
+        let cap = cap.unwrap_or_else(|| bitmap.capacity());
+        Self { bitmap, cap }
+    }
+
+    /// Returns the first run of `len` free entries starting at or after `goal`, wrapping around to
+    /// the start of the bitmap. If no run is that long the longest run found is returned instead.
+    /// Returns `None` if every entry is in use.
+    pub fn find(&self, goal: usize, len: usize) -> Option<Range<usize>> {
+        let goal = if goal < self.cap { goal } else { 0 };
+        let mut longest = 0..0;
+        for (start, end) in [(goal, self.cap), (0, goal)].iter().copied() {
+            let mut run = start..start;
+            for i in start..end {
+                if let State::Used = self.bitmap.get(i) {
+                    run = i + 1..i + 1;
+                    continue;
+                }
+                run.end = i + 1;
+                if run.len() == len {
+                    return Some(run);
+                }
+                if run.len() > longest.len() {
+                    longest = run.clone();
+                }
+            }
+        }
+        Some(longest).filter(|longest| longest.start < longest.end)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn can_read_and_write_values_to_bitmap() {
-        let mut bmp = Bitmap::new();
+        let mut bmp = Bitmap::new(1);
 
         bmp.set_reserved(2);
 
@@ -130,18 +217,31 @@ mod tests {
 
     #[test]
     fn can_set_values_at_ends_of_bitmap() {
-        let mut bmp = Bitmap::new();
+        let mut bmp = Bitmap::new(1);
 
         bmp.set_reserved(0);
         bmp.set_reserved(4095);
+        bmp.set_reserved(BLOCK_SIZE * 8 - 1);
 
         assert_eq!(bmp.get(0), State::Used);
         assert_eq!(bmp.get(4095), State::Used);
+        assert_eq!(bmp.get(BLOCK_SIZE * 8 - 1), State::Used);
+    }
+
+    #[test]
+    fn counts_free_entries_in_range() {
+        let mut bmp = Bitmap::new(1);
+        bmp.set_reserved(1);
+        bmp.set_reserved(5);
+
+        assert_eq!(bmp.count_free(0..8), 6);
+        assert_eq!(bmp.count_free(2..5), 3);
+        assert_eq!(bmp.count_free(5..6), 0);
     }
 
     #[test]
     fn can_toggle_block_between_free_and_used() {
-        let mut bmp = Bitmap::new();
+        let mut bmp = Bitmap::new(1);
 
         bmp.set_reserved(10);
         assert_eq!(bmp.get(10), State::Used);
@@ -150,20 +250,142 @@ mod tests {
         assert_eq!(bmp.get(10), State::Free);
     }
 
+    #[test]
+    fn freeing_block_leaves_neighbours_reserved() {
+        let mut bmp = Bitmap::new(1);
+        bmp.set_reserved(9);
+        bmp.set_reserved(10);
+        bmp.set_reserved(11);
+
+        bmp.set_free(10);
+
+        assert_eq!(bmp.get(9), State::Used);
+        assert_eq!(bmp.get(10), State::Free);
+        assert_eq!(bmp.get(11), State::Used);
+    }
+
     #[test]
     fn can_serialize_and_deserialize_state() {
-        let mut bmp = Bitmap::new();
+        let mut bmp = Bitmap::new(1);
         bmp.set_reserved(10);
         bmp.set_reserved(11);
         bmp.set_reserved(12);
 
-        let read_bmp = Bitmap::parse(bmp.serialize());
-        // This is a dumb way of testing equality between two arrays of different
-        // lengths. I can't derive debug for the arrays because they exceed the max
-        // trait implementation limit, see: https://doc.rust-lang.org/std/primitive.array.html.
-        bmp.bitmap.iter().zip(read_bmp.bitmap.iter()).all(|(a, b)| {
-            assert_eq!(a, b);
-            true
-        });
+        let mut read_bmp = Bitmap::new(1);
+        read_bmp.load_block(0, bmp.serialize_block(0));
+
+        assert_eq!(read_bmp.bitmap, bmp.bitmap);
+    }
+
+    #[test]
+    fn can_track_entries_across_multiple_blocks() {
+        let mut bmp = Bitmap::new(3);
+
+        bmp.set_reserved(BITS_PER_BLOCK - 1);
+        bmp.set_reserved(BITS_PER_BLOCK);
+        bmp.set_reserved(3 * BITS_PER_BLOCK - 1);
+
+        assert_eq!(bmp.capacity(), 3 * BITS_PER_BLOCK);
+        assert_eq!(bmp.get(BITS_PER_BLOCK - 2), State::Free);
+        assert_eq!(bmp.get(BITS_PER_BLOCK - 1), State::Used);
+        assert_eq!(bmp.get(BITS_PER_BLOCK), State::Used);
+        assert_eq!(bmp.get(3 * BITS_PER_BLOCK - 1), State::Used);
+    }
+
+    #[test]
+    fn changes_mark_only_their_block_dirty() {
+        let mut bmp = Bitmap::new(4);
+        assert_eq!(bmp.take_dirty_blocks(), vec![0, 1, 2, 3]);
+
+        bmp.set_reserved(5);
+        bmp.set_free(2 * BITS_PER_BLOCK + 7);
+
+        assert_eq!(bmp.take_dirty_blocks(), vec![0, 2]);
+        assert!(bmp.take_dirty_blocks().is_empty());
+    }
+
+    #[test]
+    fn blocks_load_independently() {
+        let mut bmp = Bitmap::new(2);
+        bmp.set_reserved(1);
+        bmp.set_reserved(BITS_PER_BLOCK + 1);
+
+        let mut read_bmp = Bitmap::new(2);
+        read_bmp.load_block(1, bmp.serialize_block(1));
+
+        assert_eq!(read_bmp.get(1), State::Free);
+        assert_eq!(read_bmp.get(BITS_PER_BLOCK + 1), State::Used);
+    }
+
+    #[test]
+    #[should_panic]
+    fn accessing_beyond_capacity_panics() {
+        Bitmap::new(1).get(BITS_PER_BLOCK);
+    }
+
+    #[test]
+    fn allocation_continues_into_later_blocks() {
+        let mut bmp = Bitmap::new(2);
+        for i in 0..BITS_PER_BLOCK {
+            bmp.set_reserved(i);
+        }
+
+        let mut allocs = NextAvailableAllocation::new(&bmp, None);
+
+        assert_eq!(allocs.next(), Some(BITS_PER_BLOCK));
+        assert_eq!(allocs.next(), Some(BITS_PER_BLOCK + 1));
+    }
+
+    #[test]
+    fn allocation_can_start_past_the_first_entry() {
+        let bmp = Bitmap::new(1);
+
+        let mut allocs = NextAvailableAllocation::new(&bmp, Some(64)).starting_at(60);
+
+        assert_eq!(allocs.next(), Some(60));
+        assert!(allocs.eq(61..64));
+    }
+
+    #[test]
+    fn contiguous_allocation_prefers_goal() {
+        let mut bmp = Bitmap::new(1);
+        bmp.set_reserved(3);
+
+        let allocs = ContiguousAllocation::new(&bmp, Some(64));
+
+        assert_eq!(allocs.find(4, 8), Some(4..12));
+        assert_eq!(allocs.find(0, 8), Some(4..12));
+        assert_eq!(allocs.find(0, 3), Some(0..3));
+    }
+
+    #[test]
+    fn contiguous_allocation_wraps_around_to_start() {
+        let mut bmp = Bitmap::new(1);
+        for i in 10..64 {
+            bmp.set_reserved(i);
+        }
+
+        let allocs = ContiguousAllocation::new(&bmp, Some(64));
+
+        assert_eq!(allocs.find(20, 4), Some(0..4));
+    }
+
+    #[test]
+    fn contiguous_allocation_falls_back_to_longest_run() {
+        let mut bmp = Bitmap::new(1);
+        for i in 0..64 {
+            bmp.set_reserved(i);
+        }
+        for i in [2, 3, 10, 11, 12, 40].iter() {
+            bmp.set_free(*i);
+        }
+
+        let allocs = ContiguousAllocation::new(&bmp, Some(64));
+
+        assert_eq!(allocs.find(0, 8), Some(10..13));
+        for i in [2, 3, 10, 11, 12, 40].iter() {
+            bmp.set_reserved(*i);
+        }
+        assert_eq!(ContiguousAllocation::new(&bmp, Some(64)).find(0, 1), None);
     }
 }
@@ -0,0 +1,29 @@
+use std::time::SystemTime;
+
+/// A source of the current time, used to stamp the times of files as they change. The clock a file
+/// system reads from is chosen with `MountOptions::with_clock`, letting tests substitute a clock
+/// they control.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// Reads the current time from the operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// How `SFS::utimens` updates a timestamp, mirroring `UTIME_NOW` and `UTIME_OMIT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUpdate {
+    /// Set the timestamp to the current time of the file system's clock.
+    Now,
+    /// Leave the timestamp unchanged.
+    Omit,
+    /// Set the timestamp to the time given.
+    To(SystemTime),
+}
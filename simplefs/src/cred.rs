@@ -0,0 +1,146 @@
+use crate::node::{Inode, STICKY};
+
+/// Permission to read a file or list a directory.
+pub const READ: u16 = 0o4;
+/// Permission to write a file or add and remove entries of a directory.
+pub const WRITE: u16 = 0o2;
+/// Permission to execute a file or search a directory.
+pub const EXECUTE: u16 = 0o1;
+
+/// The identity file system calls are made with, checked against the owner, group and permission
+/// bits of the files they touch like the credentials of a process. Set when mounting with
+/// `MountOptions::with_credentials` or later with `SFS::set_credentials`.
+///
+/// The defaults are those of root, who may read and write anything.
+///
+/// ```
+/// use simplefs::Credentials;
+///
+/// let creds = Credentials::new(1000, 1000).with_groups(vec![27, 100]);
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// The supplementary groups the caller is a member of besides `gid`.
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            groups: vec![],
+        }
+    }
+
+    /// Sets the supplementary groups.
+    pub fn with_groups(mut self, groups: Vec<u32>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Whether these are the credentials of root, who is exempt from most permission checks.
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether the caller is a member of the group, either as their primary or a supplementary
+    /// group.
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Whether the caller owns the inode, or is root and so may act as if they did.
+    pub(crate) fn owns(&self, node: &Inode) -> bool {
+        self.is_root() || self.uid == u32::from(node.uid)
+    }
+
+    /// Whether the caller is granted every one of the `access` bits, a combination of `READ`,
+    /// `WRITE` and `EXECUTE`, on the inode. Only the owner, group or other bits apply, whichever
+    /// matches the caller first. Root may read and write anything, search any directory and
+    /// execute any file someone may execute.
+    pub(crate) fn can_access(&self, node: &Inode, access: u16) -> bool {
+        if self.is_root() {
+            return access & EXECUTE == 0 || node.is_dir() || node.mode & 0o111 != 0;
+        }
+
+        let granted = if self.uid == u32::from(node.uid) {
+            node.mode >> 6
+        } else if self.in_group(u32::from(node.gid)) {
+            node.mode >> 3
+        } else {
+            node.mode
+        };
+        granted & access == access
+    }
+
+    /// Whether the caller may remove or rename the entry for `node` in the directory `dir`, which
+    /// they already have write permission on. Entries of a sticky directory can only be removed by
+    /// the owner of the entry, the owner of the directory or root.
+    pub(crate) fn can_remove(&self, dir: &Inode, node: &Inode) -> bool {
+        dir.mode & STICKY == 0 || self.owns(dir) || self.owns(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::Bitmap;
+    use crate::node::InodeGroup;
+
+    fn node(mode: u16, uid: u16, gid: u16) -> Inode {
+        let mut group = InodeGroup::new(Bitmap::new(1), 16, 0);
+        let inum = group.new_file().unwrap();
+        let node = group.get_mut(inum).unwrap();
+        node.set_permissions(mode);
+        node.uid = uid;
+        node.gid = gid;
+        *node
+    }
+
+    #[test]
+    fn owner_group_and_other_bits_apply_in_turn() {
+        let file = node(0o640, 1000, 100);
+
+        let owner = Credentials::new(1000, 1000);
+        assert!(owner.can_access(&file, READ | WRITE));
+        assert!(!owner.can_access(&file, EXECUTE));
+
+        let member = Credentials::new(1001, 1001).with_groups(vec![100]);
+        assert!(member.can_access(&file, READ));
+        assert!(!member.can_access(&file, WRITE));
+
+        let other = Credentials::new(1002, 1002);
+        assert!(!other.can_access(&file, READ));
+    }
+
+    #[test]
+    fn owner_bits_apply_even_when_group_grants_more() {
+        let file = node(0o070, 1000, 100);
+
+        assert!(!Credentials::new(1000, 100).can_access(&file, READ));
+    }
+
+    #[test]
+    fn root_may_only_execute_executable_files() {
+        let root = Credentials::default();
+
+        assert!(root.can_access(&node(0o000, 1000, 100), READ | WRITE));
+        assert!(!root.can_access(&node(0o644, 1000, 100), EXECUTE));
+        assert!(root.can_access(&node(0o010, 1000, 100), EXECUTE));
+    }
+
+    #[test]
+    fn sticky_directories_only_let_owners_remove_entries() {
+        let dir = node(STICKY | 0o777, 1, 1);
+        let file = node(0o644, 1000, 1000);
+
+        assert!(Credentials::new(1000, 1000).can_remove(&dir, &file));
+        assert!(Credentials::new(1, 1).can_remove(&dir, &file));
+        assert!(Credentials::default().can_remove(&dir, &file));
+        assert!(!Credentials::new(1001, 1000).can_remove(&dir, &file));
+        assert!(Credentials::new(1001, 1000).can_remove(&node(0o777, 1, 1), &file));
+    }
+}
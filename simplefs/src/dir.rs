@@ -0,0 +1,643 @@
+use crate::fs::{SFSError, BLOCK_SIZE, SFS};
+use crate::io::BlockStorage;
+use crate::metadata::FileType;
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::mem::size_of;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use zerocopy::{AsBytes, FromBytes};
+
+/// The longest name, in bytes, a directory entry can hold.
+pub const MAX_NAME_LEN: usize = 255;
+const HEADER_SIZE: usize = size_of::<EntryHeader>();
+
+const REGULAR_FILE_TYPE: u8 = 1;
+const DIRECTORY_TYPE: u8 = 2;
+const SYMLINK_TYPE: u8 = 7;
+
+/// The fixed part of a directory entry record, followed on disk by the raw bytes of the name.
+///
+/// Directories are made up of whole blocks, each holding a chain of records that never crosses
+/// into the next block. The record length of each entry reaches to the start of the next, so the
+/// last record of a block takes up whatever the block has left. A record with an empty name is an
+/// unused slot; inode numbers can not mark them as inode zero is the root directory.
+#[repr(C)]
+#[derive(AsBytes, FromBytes, Debug, Default, Clone, Copy)]
+struct EntryHeader {
+    inum: u32,
+    /// The length of the whole record in bytes, a multiple of 4.
+    rec_len: u16,
+    name_len: u8,
+    file_type: u8,
+}
+
+/// A single entry of a directory listing returned by `SFS::read_dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name the file is linked under in the directory.
+    pub name: OsString,
+    /// The inode number the entry links to.
+    pub inum: u32,
+    /// The kind of file the entry links to.
+    pub file_type: FileType,
+}
+
+/// Iterates over the entries of a directory, including its "." and ".." entries.
+///
+/// Directory contents are read a block at a time as the iterator advances so listing a
+/// directory never requires holding the whole directory in memory.
+pub struct ReadDir<'a, T: BlockStorage> {
+    fs: &'a mut SFS<T>,
+    dir: u32,
+    /// The byte offset into the directory of the next block to read.
+    offset: usize,
+    /// Entries parsed from the last block read that have not been returned yet.
+    entries: VecDeque<DirEntry>,
+}
+
+impl<'a, T: BlockStorage> ReadDir<'a, T> {
+    pub(crate) fn new(fs: &'a mut SFS<T>, dir: u32) -> Self {
+        Self {
+            fs,
+            dir,
+            offset: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Reads the next block of the directory, queueing every entry in it. Returns false once the
+    /// end of the directory has been reached.
+    fn read_block(&mut self) -> Result<bool, SFSError> {
+        let mut block_buf = [0; BLOCK_SIZE];
+        let read = self.fs.read_inode(self.dir, self.offset, &mut block_buf)?;
+        if read == 0 {
+            return Ok(false);
+        }
+        if read != BLOCK_SIZE {
+            return Err(SFSError::CorruptDirectory);
+        }
+        self.offset += read;
+
+        self.entries.extend(parse_block(&block_buf)?);
+        Ok(true)
+    }
+}
+
+impl<'a, T: BlockStorage> Iterator for ReadDir<'a, T> {
+    type Item = Result<DirEntry, SFSError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() {
+            match self.read_block() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+/// Checks that `name` can be stored in a directory entry: it must be non-empty, at most
+/// `MAX_NAME_LEN` bytes and free of NUL bytes and slashes.
+pub fn validate_name(name: &OsStr) -> Result<(), SFSError> {
+    let bytes = name.as_bytes();
+    if bytes.len() > MAX_NAME_LEN {
+        return Err(SFSError::NameTooLong);
+    }
+    if bytes.is_empty() || !valid_name_bytes(bytes) {
+        return Err(SFSError::InvalidArgument(format!(
+            "{:?} is not a valid file name",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn valid_name_bytes(bytes: &[u8]) -> bool {
+    !bytes.iter().any(|&b| b == b'\0' || b == b'/')
+}
+
+/// The space taken up by a record holding a name of `name_len` bytes, keeping records 4-byte
+/// aligned.
+fn record_len(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+/// The space the entry takes up in a directory block.
+pub fn entry_len(entry: &DirEntry) -> usize {
+    record_len(entry.name.len())
+}
+
+/// Whether the entries fit in a single directory block.
+pub fn fits_block(entries: &[DirEntry]) -> bool {
+    entries.iter().map(entry_len).sum::<usize>() <= BLOCK_SIZE
+}
+
+fn encode_file_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::RegularFile => REGULAR_FILE_TYPE,
+        FileType::Directory => DIRECTORY_TYPE,
+        FileType::Symlink => SYMLINK_TYPE,
+    }
+}
+
+fn decode_file_type(file_type: u8) -> Option<FileType> {
+    match file_type {
+        REGULAR_FILE_TYPE => Some(FileType::RegularFile),
+        DIRECTORY_TYPE => Some(FileType::Directory),
+        SYMLINK_TYPE => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+/// Encodes the entries as a sequence of directory blocks, starting a new block whenever the next
+/// record would not fit in the current one. The names must have passed `validate_name`.
+pub fn serialize_entries(entries: &[DirEntry]) -> Vec<u8> {
+    let mut contents = vec![];
+    let mut block = vec![];
+    let mut used = 0;
+    for entry in entries {
+        let len = record_len(entry.name.len());
+        if used + len > BLOCK_SIZE {
+            push_block(&mut contents, &block);
+            block.clear();
+            used = 0;
+        }
+        block.push(entry);
+        used += len;
+    }
+    push_block(&mut contents, &block);
+    contents
+}
+
+/// Appends a block holding the entries, the last record growing to fill the rest of the block.
+fn push_block(contents: &mut Vec<u8>, entries: &[&DirEntry]) {
+    let block_start = contents.len();
+    contents.resize(block_start + BLOCK_SIZE, 0);
+    let block = &mut contents[block_start..];
+    if entries.is_empty() {
+        let unused = EntryHeader {
+            rec_len: BLOCK_SIZE as u16,
+            ..EntryHeader::default()
+        };
+        write_header(block, 0, &unused);
+    }
+
+    let mut offset = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let rec_len = if i + 1 == entries.len() {
+            BLOCK_SIZE - offset
+        } else {
+            entry_len(entry)
+        };
+        write_record(block, offset, rec_len, entry);
+        offset += rec_len;
+    }
+}
+
+/// Decodes the contents of a directory written before `FEATURE_DIR_RECORDS`, one `inum:name` line
+/// per entry.
+pub fn parse_legacy_entries(contents: &[u8]) -> Result<Vec<(OsString, u32)>, SFSError> {
+    let contents = std::str::from_utf8(contents).map_err(|_| SFSError::CorruptDirectory)?;
+    contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let inum = parts
+                .next()
+                .and_then(|inum| inum.parse::<u32>().ok())
+                .ok_or(SFSError::CorruptDirectory)?;
+            let name = parts.next().ok_or(SFSError::CorruptDirectory)?;
+            Ok((OsString::from(name), inum))
+        })
+        .collect()
+}
+
+/// Decodes the entries held in a directory block, skipping unused slots. Fails if any record is
+/// malformed rather than trusting lengths read from disk.
+pub fn parse_block(block: &[u8]) -> Result<Vec<DirEntry>, SFSError> {
+    Ok(records(block)?
+        .into_iter()
+        .filter(|(_, header)| header.name_len > 0)
+        .map(|(offset, header)| DirEntry {
+            name: OsString::from_vec(record_name(block, offset, &header).to_vec()),
+            inum: header.inum,
+            // Checked by `records`.
+            file_type: decode_file_type(header.file_type).unwrap(),
+        })
+        .collect())
+}
+
+/// Points the record named like the entry at the entry's inode, in place. Returns false if the
+/// block holds no such record.
+pub fn set_record(block: &mut [u8], entry: &DirEntry) -> Result<bool, SFSError> {
+    let name = entry.name.as_bytes();
+    let found = records(block)?.into_iter().find(|(offset, header)| {
+        header.name_len > 0 && record_name(block, *offset, header) == name
+    });
+    match found {
+        Some((offset, mut header)) => {
+            header.inum = entry.inum;
+            header.file_type = encode_file_type(entry.file_type);
+            write_header(block, offset, &header);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Adds a record for the entry in the first unused slot, or the space left over at the end of a
+/// record, large enough to hold it. Returns false, leaving the block unchanged, if there is no
+/// room. The block must not already hold a record of the same name.
+pub fn insert_record(block: &mut [u8], entry: &DirEntry) -> Result<bool, SFSError> {
+    let needed = entry_len(entry);
+    for (offset, mut header) in records(block)? {
+        let used = if header.name_len == 0 {
+            0
+        } else {
+            record_len(header.name_len as usize)
+        };
+        let free = header.rec_len as usize - used;
+        if free < needed {
+            continue;
+        }
+
+        if used > 0 {
+            header.rec_len = used as u16;
+            write_header(block, offset, &header);
+        }
+        write_record(block, offset + used, free, entry);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Removes the record named `name`, returning the inode it linked to. Its space is handed to the
+/// record before it, or left as an unused slot if it was the first record of the block.
+pub fn remove_record(block: &mut [u8], name: &OsStr) -> Result<Option<u32>, SFSError> {
+    let records = records(block)?;
+    let pos = records.iter().position(|(offset, header)| {
+        header.name_len > 0 && record_name(block, *offset, header) == name.as_bytes()
+    });
+    let pos = match pos {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+
+    let (offset, header) = records[pos];
+    let rec_len = header.rec_len as usize;
+    for byte in &mut block[offset..offset + rec_len] {
+        *byte = 0;
+    }
+    match pos.checked_sub(1).map(|prev| records[prev]) {
+        Some((prev_offset, mut prev)) => {
+            prev.rec_len += header.rec_len;
+            write_header(block, prev_offset, &prev);
+        }
+        None => {
+            let unused = EntryHeader {
+                rec_len: header.rec_len,
+                ..EntryHeader::default()
+            };
+            write_header(block, offset, &unused);
+        }
+    }
+    Ok(Some(header.inum))
+}
+
+/// Walks the chain of records making up a directory block, returning the offset and header of
+/// each. Fails if any record is malformed rather than trusting lengths read from disk.
+fn records(block: &[u8]) -> Result<Vec<(usize, EntryHeader)>, SFSError> {
+    let mut records = vec![];
+    let mut offset = 0;
+    while offset < block.len() {
+        let record = &block[offset..];
+        if record.len() < HEADER_SIZE {
+            return Err(SFSError::CorruptDirectory);
+        }
+        let header = read_header(block, offset);
+        let rec_len = header.rec_len as usize;
+        let name_len = header.name_len as usize;
+        if rec_len & 3 != 0 || rec_len < record_len(name_len) || rec_len > record.len() {
+            return Err(SFSError::CorruptDirectory);
+        }
+        if name_len > 0
+            && (!valid_name_bytes(record_name(block, offset, &header))
+                || decode_file_type(header.file_type).is_none())
+        {
+            return Err(SFSError::CorruptDirectory);
+        }
+
+        records.push((offset, header));
+        offset += rec_len;
+    }
+    Ok(records)
+}
+
+fn record_name<'a>(block: &'a [u8], offset: usize, header: &EntryHeader) -> &'a [u8] {
+    let start = offset + HEADER_SIZE;
+    &block[start..start + header.name_len as usize]
+}
+
+fn read_header(block: &[u8], offset: usize) -> EntryHeader {
+    let mut header = EntryHeader::default();
+    header
+        .as_bytes_mut()
+        .copy_from_slice(&block[offset..offset + HEADER_SIZE]);
+    header
+}
+
+fn write_header(block: &mut [u8], offset: usize, header: &EntryHeader) {
+    block[offset..offset + HEADER_SIZE].copy_from_slice(header.as_bytes());
+}
+
+/// Writes a record of `rec_len` bytes for the entry at `offset`, zeroing any space past its name.
+fn write_record(block: &mut [u8], offset: usize, rec_len: usize, entry: &DirEntry) {
+    let name = entry.name.as_bytes();
+    let header = EntryHeader {
+        inum: entry.inum,
+        rec_len: rec_len as u16,
+        name_len: name.len() as u8,
+        file_type: encode_file_type(entry.file_type),
+    };
+    let record = &mut block[offset..offset + rec_len];
+    for byte in record.iter_mut() {
+        *byte = 0;
+    }
+    record[..HEADER_SIZE].copy_from_slice(header.as_bytes());
+    record[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::OpenMode;
+    use crate::io::{FileBlockEmulator, FileBlockEmulatorBuilder};
+    use std::path::Path;
+
+    fn create_test_device() -> FileBlockEmulator {
+        let dev = tempfile::tempfile().unwrap();
+        FileBlockEmulatorBuilder::from(dev)
+            .with_block_size(64)
+            .build()
+            .expect("Could not initialize disk emulator.")
+    }
+
+    fn sorted_names<T: BlockStorage>(entries: ReadDir<'_, T>) -> Vec<OsString> {
+        let mut names: Vec<OsString> = entries.map(|entry| entry.unwrap().name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn lists_entries_with_file_types() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        let mut entries: Vec<DirEntry> = fs.read_dir("/").unwrap().map(Result::unwrap).collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let listing: Vec<(&str, FileType)> = entries
+            .iter()
+            .map(|entry| (entry.name.to_str().unwrap(), entry.file_type))
+            .collect();
+        assert_eq!(
+            listing,
+            vec![
+                (".", FileType::Directory),
+                ("..", FileType::Directory),
+                ("a", FileType::Directory),
+                ("foo", FileType::RegularFile),
+            ]
+        );
+        assert_eq!(entries[0].inum, 0);
+    }
+
+    #[test]
+    fn lists_symlinks_with_their_type() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.symlink("/", "/root").unwrap();
+
+        let entry = fs
+            .read_dir("/")
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.name == "root")
+            .unwrap();
+
+        assert_eq!(entry.file_type, FileType::Symlink);
+    }
+
+    #[test]
+    fn lists_entries_spanning_many_blocks() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let mut expected = vec![OsString::from("."), OsString::from("..")];
+        for i in 0..40 {
+            let name = format!("{:0>150}", i);
+            fs.open(format!("/{}", name), OpenMode::CREATE).unwrap();
+            expected.push(OsString::from(name));
+        }
+        expected.sort();
+
+        assert!(fs.stat("/").unwrap().len() > BLOCK_SIZE as u64);
+        assert_eq!(sorted_names(fs.read_dir("/").unwrap()), expected);
+    }
+
+    #[test]
+    fn read_dir_of_regular_file_returns_error() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        match fs.read_dir("/foo") {
+            Err(SFSError::NotADirectory) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
+    #[test]
+    fn names_with_arbitrary_bytes_round_trip() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let names = vec![
+            OsString::from("a:b"),
+            OsString::from("line\nbreak"),
+            OsString::from_vec(vec![0xff, 0xfe, b'x']),
+            OsString::from("n".repeat(MAX_NAME_LEN)),
+        ];
+        for name in &names {
+            let path = Path::new("/").join(name);
+            fs.open(&path, OpenMode::CREATE).unwrap();
+            assert!(fs.stat(&path).unwrap().is_file());
+        }
+
+        let mut expected = names.clone();
+        expected.push(OsString::from("."));
+        expected.push(OsString::from(".."));
+        expected.sort();
+        assert_eq!(sorted_names(fs.read_dir("/").unwrap()), expected);
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+
+        match fs.open(
+            format!("/{}", "n".repeat(MAX_NAME_LEN + 1)),
+            OpenMode::CREATE,
+        ) {
+            Err(SFSError::NameTooLong) => (),
+            _ => panic!("Unexpected result."),
+        }
+        match fs.mkdir("/nul\0byte") {
+            Err(SFSError::InvalidArgument(_)) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
+    #[test]
+    fn serialized_records_fill_whole_blocks() {
+        let entries: Vec<DirEntry> = (0..300)
+            .map(|i| DirEntry {
+                name: OsString::from(format!("entry{}", i)),
+                inum: i,
+                file_type: FileType::RegularFile,
+            })
+            .collect();
+
+        let contents = serialize_entries(&entries);
+
+        assert_eq!(contents.len() % BLOCK_SIZE, 0);
+        assert!(contents.len() > BLOCK_SIZE);
+        let parsed: Vec<DirEntry> = contents
+            .chunks(BLOCK_SIZE)
+            .flat_map(|block| parse_block(block).unwrap())
+            .collect();
+        assert_eq!(parsed, entries);
+        assert_eq!(parse_block(&serialize_entries(&[])).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn malformed_records_are_reported_as_corrupt() {
+        let entry = DirEntry {
+            name: OsString::from("foo"),
+            inum: 3,
+            file_type: FileType::Directory,
+        };
+        let valid = serialize_entries(&[entry]);
+        // Bytes overwritten at an offset into the block.
+        let corruptions: Vec<(usize, &[u8])> = vec![
+            // A zero record length.
+            (4, &[0, 0]),
+            // A record running past the end of the block.
+            (4, &[0, 0x20]),
+            // A record too short for its name.
+            (6, &[200]),
+            // An unknown file type.
+            (7, &[9]),
+            // A slash in the name.
+            (HEADER_SIZE, b"/"),
+        ];
+
+        for (offset, bytes) in corruptions {
+            let mut block = valid.clone();
+            block[offset..offset + bytes.len()].copy_from_slice(bytes);
+            match parse_block(&block) {
+                Err(SFSError::CorruptDirectory) => (),
+                _ => panic!("Unexpected result."),
+            }
+        }
+    }
+
+    fn file(name: &str, inum: u32) -> DirEntry {
+        DirEntry {
+            name: OsString::from(name),
+            inum,
+            file_type: FileType::RegularFile,
+        }
+    }
+
+    fn names(block: &[u8]) -> Vec<OsString> {
+        parse_block(block)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    #[test]
+    fn records_are_inserted_into_free_space() {
+        let mut block = serialize_entries(&[file("a", 1)]);
+
+        assert!(insert_record(&mut block, &file("b", 2)).unwrap());
+        assert!(insert_record(&mut block, &file("c", 3)).unwrap());
+
+        assert_eq!(names(&block), vec!["a", "b", "c"]);
+        let headers = records(&block).unwrap();
+        assert_eq!(headers[0].1.rec_len, 12);
+        assert_eq!(headers[1].1.rec_len, 12);
+        assert_eq!(headers[2].1.rec_len as usize, BLOCK_SIZE - 24);
+    }
+
+    #[test]
+    fn removed_records_are_coalesced_and_reused() {
+        let mut block = serialize_entries(&[file("a", 1), file("b", 2), file("c", 3)]);
+
+        assert_eq!(remove_record(&mut block, OsStr::new("b")).unwrap(), Some(2));
+        assert_eq!(records(&block).unwrap()[0].1.rec_len, 24);
+        assert_eq!(remove_record(&mut block, OsStr::new("b")).unwrap(), None);
+
+        // The space left by "b" is reused ahead of the end of the block.
+        assert!(insert_record(&mut block, &file("d", 4)).unwrap());
+        assert_eq!(names(&block), vec!["a", "d", "c"]);
+
+        // Removing the first record leaves an unused slot in its place.
+        assert_eq!(remove_record(&mut block, OsStr::new("a")).unwrap(), Some(1));
+        assert_eq!(names(&block), vec!["d", "c"]);
+        assert!(insert_record(&mut block, &file("e", 5)).unwrap());
+        assert_eq!(names(&block), vec!["e", "d", "c"]);
+    }
+
+    #[test]
+    fn set_record_repoints_existing_name() {
+        let mut block = serialize_entries(&[file("a", 1), file("b", 2)]);
+        let dir = DirEntry {
+            file_type: FileType::Directory,
+            ..file("b", 9)
+        };
+
+        assert!(set_record(&mut block, &dir).unwrap());
+        assert!(!set_record(&mut block, &file("c", 3)).unwrap());
+
+        assert_eq!(parse_block(&block).unwrap(), vec![file("a", 1), dir]);
+    }
+
+    #[test]
+    fn insert_into_full_block_leaves_it_unchanged() {
+        let long = |i: u32| file(&format!("{:0>255}", i), i);
+        let entries: Vec<DirEntry> = (0..15).map(long).collect();
+        let mut block = serialize_entries(&entries);
+        assert_eq!(block.len(), BLOCK_SIZE);
+
+        let before = block.clone();
+        assert!(!insert_record(&mut block, &long(15)).unwrap());
+        assert_eq!(block, before);
+    }
+
+    #[test]
+    fn legacy_text_entries_are_parsed() {
+        let entries = parse_legacy_entries(b"0:.\n0:..\n4:a:b\n").unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                (OsString::from("."), 0),
+                (OsString::from(".."), 0),
+                (OsString::from("a:b"), 4),
+            ]
+        );
+        assert!(parse_legacy_entries(b"x:foo\n").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::fs::BLOCK_SIZE;
+use crate::node::DIRECT_BLOCKS;
+
+use std::cmp::Ordering;
+use std::mem::size_of;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The number of extents that fit in the block pointer space of an inode.
+pub const INLINE_EXTENTS: usize = DIRECT_BLOCKS * 4 / size_of::<Extent>();
+/// The number of extents held by each block of an extent index.
+pub const EXTENTS_PER_BLOCK: usize = BLOCK_SIZE / size_of::<Extent>();
+
+/// A run of contiguous disk blocks backing a run of a file's logical blocks.
+///
+/// Once a file has more extents than fit in its inode they move to blocks of their own and the
+/// inode holds an index of those blocks instead, each index entry reusing this layout with `start`
+/// pointing at the index block and `len` counting the extents held in it.
+#[repr(C)]
+#[derive(AsBytes, FromBytes, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// The first logical block of the file covered by the extent.
+    pub logical: u32,
+    /// The disk block backing the first logical block.
+    pub start: u32,
+    /// The number of blocks in the run, zero marks an unused slot.
+    pub len: u32,
+}
+
+impl Extent {
+    /// The logical block following the last one covered by the extent.
+    pub fn end(&self) -> u32 {
+        self.logical + self.len
+    }
+}
+
+/// Decodes the extents packed into an inode's block pointer space.
+pub fn unpack(words: &[u32; DIRECT_BLOCKS]) -> Vec<Extent> {
+    let mut extents = vec![Extent::default(); INLINE_EXTENTS];
+    let len = extents.as_bytes().len();
+    extents
+        .as_bytes_mut()
+        .copy_from_slice(&words.as_bytes()[..len]);
+    extents.retain(|extent| extent.len != 0);
+    extents
+}
+
+/// Encodes at most `INLINE_EXTENTS` extents into an inode's block pointer space.
+pub fn pack(extents: &[Extent]) -> [u32; DIRECT_BLOCKS] {
+    assert!(extents.len() <= INLINE_EXTENTS);
+    let mut words = [0; DIRECT_BLOCKS];
+    let bytes = extents.as_bytes();
+    words.as_bytes_mut()[..bytes.len()].copy_from_slice(bytes);
+    words
+}
+
+/// Decodes the first `count` extents of an extent index block.
+pub fn parse_block(buf: &[u8], count: usize) -> Vec<Extent> {
+    let mut extents = vec![Extent::default(); count];
+    let len = extents.as_bytes().len();
+    extents.as_bytes_mut().copy_from_slice(&buf[..len]);
+    extents
+}
+
+/// Encodes at most `EXTENTS_PER_BLOCK` extents as an extent index block.
+pub fn serialize_block(extents: &[Extent]) -> Vec<u8> {
+    assert!(extents.len() <= EXTENTS_PER_BLOCK);
+    let mut block_buf = vec![0; BLOCK_SIZE];
+    let bytes = extents.as_bytes();
+    block_buf[..bytes.len()].copy_from_slice(bytes);
+    block_buf
+}
+
+/// Returns the disk block backing logical block `index`, if any. Extents must be sorted.
+pub fn lookup(extents: &[Extent], index: u32) -> Option<u32> {
+    let pos = position(extents, |extent| extent.end() <= index);
+    extents
+        .get(pos)
+        .filter(|extent| extent.logical <= index)
+        .map(|extent| extent.start + (index - extent.logical))
+}
+
+/// Returns whether logical block `index` is mapped along with the number of blocks from it on that
+/// share its state, up to the end of its extent or the start of the next one. Extents must be
+/// sorted.
+pub fn run(extents: &[Extent], index: u32) -> (bool, u32) {
+    let pos = position(extents, |extent| extent.end() <= index);
+    match extents.get(pos) {
+        Some(extent) if extent.logical <= index => (true, extent.end() - index),
+        Some(extent) => (false, extent.logical - index),
+        None => (false, std::u32::MAX - index),
+    }
+}
+
+/// Returns the first logical block after `index` that is already mapped.
+pub fn next_mapped(extents: &[Extent], index: u32) -> Option<u32> {
+    extents
+        .iter()
+        .map(|extent| extent.logical)
+        .find(|&logical| logical > index)
+}
+
+/// Returns the disk block that would continue the closest extent before `index`, allocating there
+/// keeps the file contiguous on disk.
+pub fn goal(extents: &[Extent], index: u32) -> Option<u32> {
+    extents
+        .iter()
+        .rev()
+        .find(|extent| extent.logical <= index)
+        .map(|extent| extent.start + (index - extent.logical))
+}
+
+/// Returns the position of the first extent `before` is false for, `before` holding for every
+/// extent ahead of it like `slice::partition_point`, which is newer than the oldest compiler the
+/// crate supports.
+fn position<F: Fn(&Extent) -> bool>(extents: &[Extent], before: F) -> usize {
+    extents
+        .binary_search_by(|extent| {
+            if before(extent) {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        })
+        .unwrap_or_else(|pos| pos)
+}
+
+/// Adds a newly mapped run to a sorted list of extents, merging it into its neighbours when they
+/// are contiguous both in the file and on disk.
+pub fn insert(extents: &mut Vec<Extent>, extent: Extent) {
+    let pos = position(extents, |existing| existing.logical < extent.logical);
+    extents.insert(pos, extent);
+
+    if pos + 1 < extents.len() && contiguous(&extents[pos], &extents[pos + 1]) {
+        extents[pos].len += extents[pos + 1].len;
+        extents.remove(pos + 1);
+    }
+    if pos > 0 && contiguous(&extents[pos - 1], &extents[pos]) {
+        extents[pos - 1].len += extents[pos].len;
+        extents.remove(pos);
+    }
+}
+
+fn contiguous(first: &Extent, second: &Extent) -> bool {
+    first.end() == second.logical && first.start + first.len == second.start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(logical: u32, start: u32, len: u32) -> Extent {
+        Extent {
+            logical,
+            start,
+            len,
+        }
+    }
+
+    #[test]
+    fn can_pack_and_unpack_inline_extents() {
+        let extents = vec![extent(0, 100, 4), extent(10, 7, 1)];
+
+        let words = pack(&extents);
+
+        assert_eq!(words[..6], [0, 100, 4, 10, 7, 1]);
+        assert_eq!(unpack(&words), extents);
+        assert_eq!(INLINE_EXTENTS, 5);
+    }
+
+    #[test]
+    fn can_serialize_and_parse_index_blocks() {
+        let extents: Vec<Extent> = (0..EXTENTS_PER_BLOCK as u32)
+            .map(|i| extent(i * 2, i + 50, 1))
+            .collect();
+
+        let block = serialize_block(&extents);
+
+        assert_eq!(block.len(), BLOCK_SIZE);
+        assert_eq!(parse_block(&block, extents.len()), extents);
+    }
+
+    #[test]
+    fn lookup_maps_blocks_within_extents() {
+        let extents = vec![extent(0, 100, 4), extent(10, 7, 2)];
+
+        assert_eq!(lookup(&extents, 0), Some(100));
+        assert_eq!(lookup(&extents, 3), Some(103));
+        assert_eq!(lookup(&extents, 4), None);
+        assert_eq!(lookup(&extents, 11), Some(8));
+        assert_eq!(lookup(&extents, 12), None);
+    }
+
+    #[test]
+    fn run_spans_extents_and_the_gaps_between_them() {
+        let extents = vec![extent(0, 100, 4), extent(10, 7, 2)];
+
+        assert_eq!(run(&extents, 1), (true, 3));
+        assert_eq!(run(&extents, 4), (false, 6));
+        assert_eq!(run(&extents, 10), (true, 2));
+        assert_eq!(run(&extents, 12), (false, std::u32::MAX - 12));
+    }
+
+    #[test]
+    fn goal_continues_preceding_extent() {
+        let extents = vec![extent(0, 100, 4), extent(10, 7, 2)];
+
+        assert_eq!(goal(&extents, 4), Some(104));
+        assert_eq!(goal(&extents, 20), Some(17));
+        assert_eq!(next_mapped(&extents, 4), Some(10));
+        assert_eq!(next_mapped(&extents, 10), None);
+    }
+
+    #[test]
+    fn contiguous_runs_merge_on_insert() {
+        let mut extents = vec![extent(0, 100, 4), extent(8, 108, 2)];
+
+        insert(&mut extents, extent(4, 104, 4));
+        assert_eq!(extents, vec![extent(0, 100, 10)]);
+
+        insert(&mut extents, extent(10, 200, 1));
+        insert(&mut extents, extent(20, 111, 1));
+        assert_eq!(
+            extents,
+            vec![extent(0, 100, 10), extent(10, 200, 1), extent(20, 111, 1)]
+        );
+    }
+}
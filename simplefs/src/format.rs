@@ -0,0 +1,271 @@
+use crate::alloc::BITS_PER_BLOCK;
+use crate::fs::{SFSError, BLOCK_SIZE};
+use crate::sb::{SuperBlock, FEATURE_DIR_RECORDS, FEATURE_EXTENTS, FEATURE_WIDE_INODES};
+
+const NODE_SIZE: usize = 256;
+const NODES_PER_BLOCK: u32 = (BLOCK_SIZE / NODE_SIZE) as u32;
+/// The superblock occupies the first block, the allocation bitmaps follow it.
+const DATA_BITMAP_START: u64 = 1;
+
+/// How the size of the inode table is chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InodeSizing {
+    /// A fixed number of inodes.
+    Count(u32),
+    /// One inode for every so many bytes of the device.
+    BytesPerInode(u64),
+}
+
+/// Describes the geometry of a file system to be created by `SFS::format`, in the spirit of the
+/// options to `mkfs`.
+///
+/// The defaults describe a 64 block file system with 80 inodes, supporting at most 80 file system
+/// objects (files or directories).
+///
+/// ```
+/// use simplefs::FormatOptions;
+///
+/// let options = FormatOptions::new()
+///     .with_total_blocks(1024)
+///     .with_bytes_per_inode(16 * 1024)
+///     .with_reserved_blocks(8);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatOptions {
+    total_blocks: u32,
+    inodes: InodeSizing,
+    reserved_blocks: u32,
+    extents: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            total_blocks: 64,
+            inodes: InodeSizing::Count(80),
+            reserved_blocks: 0,
+            extents: false,
+        }
+    }
+}
+
+impl FormatOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of blocks on the device to format, all metadata and data blocks included.
+    pub fn with_total_blocks(mut self, blocks: u32) -> Self {
+        self.total_blocks = blocks;
+        self
+    }
+
+    /// Sets the number of inodes, and so the number of files and directories, the file system can
+    /// hold. The count is rounded up to fill whole inode table blocks.
+    pub fn with_inode_count(mut self, inodes: u32) -> Self {
+        self.inodes = InodeSizing::Count(inodes);
+        self
+    }
+
+    /// Sizes the inode table to hold one inode per `bytes` bytes of the device, overriding any
+    /// previously set inode count.
+    pub fn with_bytes_per_inode(mut self, bytes: u64) -> Self {
+        self.inodes = InodeSizing::BytesPerInode(bytes);
+        self
+    }
+
+    /// Sets the number of data blocks held back from regular allocations.
+    pub fn with_reserved_blocks(mut self, blocks: u32) -> Self {
+        self.reserved_blocks = blocks;
+        self
+    }
+
+    /// Chooses whether files map their data with extents, runs of contiguous blocks, instead of a
+    /// pointer per block. Extents keep the metadata of large sequentially written files small.
+    pub fn with_extents(mut self, enabled: bool) -> Self {
+        self.extents = enabled;
+        self
+    }
+
+    /// Lays out the file system returning the superblock describing it.
+    ///
+    /// # Layout
+    /// ==============================================================================
+    /// | SuperBlock | Bitmap (data region) | Bitmap (inodes) | Inodes | Data Region |
+    /// ==============================================================================
+    ///
+    /// Each bitmap spans as many blocks as needed to track every block of the device or every
+    /// inode respectively.
+    pub(crate) fn layout(&self, magic: u32) -> Result<SuperBlock, SFSError> {
+        let inodes = match self.inodes {
+            InodeSizing::Count(count) => u64::from(count),
+            InodeSizing::BytesPerInode(0) => {
+                return Err(SFSError::InvalidArgument(
+                    "bytes per inode must be non-zero".to_string(),
+                ))
+            }
+            InodeSizing::BytesPerInode(bytes) => {
+                u64::from(self.total_blocks) * BLOCK_SIZE as u64 / bytes
+            }
+        };
+        if inodes == 0 {
+            return Err(SFSError::InvalidArgument(
+                "file system needs at least one inode for the root directory".to_string(),
+            ));
+        }
+
+        let inode_table_blocks = (inodes - 1) / u64::from(NODES_PER_BLOCK) + 1;
+        let inodes_count = inode_table_blocks * u64::from(NODES_PER_BLOCK);
+        if inodes_count > u64::from(std::u32::MAX) {
+            return Err(SFSError::InvalidArgument(format!(
+                "at most {} inodes are supported",
+                std::u32::MAX
+            )));
+        }
+
+        let data_bitmap_blocks = bitmap_blocks(u64::from(self.total_blocks));
+        let inode_bitmap_start = DATA_BITMAP_START + data_bitmap_blocks;
+        let inode_bitmap_blocks = bitmap_blocks(inodes_count);
+        let inode_table_start = inode_bitmap_start + inode_bitmap_blocks;
+        let data_region_start = inode_table_start + inode_table_blocks;
+        // The root directory needs a block on top of any reserved blocks.
+        let min_blocks = data_region_start + u64::from(self.reserved_blocks) + 1;
+        if u64::from(self.total_blocks) < min_blocks {
+            return Err(SFSError::InvalidArgument(format!(
+                "file system needs at least {} blocks",
+                min_blocks
+            )));
+        }
+
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = magic;
+        sb.inodes_count = inodes_count as u32;
+        sb.blocks_count = self.total_blocks - data_region_start as u32;
+        sb.reserved_blocks_count = self.reserved_blocks;
+        // All blocks and inodes are initially free.
+        sb.free_blocks_count = sb.blocks_count;
+        sb.free_inodes_count = sb.inodes_count;
+        sb.total_blocks_count = self.total_blocks;
+        sb.inode_table_start = inode_table_start as u32;
+        sb.inode_table_blocks = inode_table_blocks as u32;
+        sb.data_region_start = data_region_start as u32;
+        sb.data_bitmap_start = DATA_BITMAP_START as u32;
+        sb.data_bitmap_blocks = data_bitmap_blocks as u32;
+        sb.inode_bitmap_start = inode_bitmap_start as u32;
+        sb.inode_bitmap_blocks = inode_bitmap_blocks as u32;
+        sb.feature_flags = FEATURE_WIDE_INODES | FEATURE_DIR_RECORDS;
+        if self.extents {
+            sb.feature_flags |= FEATURE_EXTENTS;
+        }
+        Ok(sb)
+    }
+}
+
+/// The number of bitmap blocks needed to track `entries` blocks or inodes.
+fn bitmap_blocks(entries: u64) -> u64 {
+    (entries.max(1) - 1) / BITS_PER_BLOCK as u64 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MAGIC: u32 = 0x4EEE;
+
+    #[test]
+    fn default_layout_matches_original_fixed_geometry() {
+        let sb = FormatOptions::default().layout(TEST_MAGIC).unwrap();
+
+        assert_eq!(sb.total_blocks_count, 64);
+        assert_eq!(sb.inodes_count, 80);
+        assert_eq!(sb.inode_table_start, 3);
+        assert_eq!(sb.inode_table_blocks, 5);
+        assert_eq!(sb.data_region_start, 8);
+        assert_eq!(sb.blocks_count, 56);
+        assert_eq!(sb.data_bitmap_start, 1);
+        assert_eq!(sb.data_bitmap_blocks, 1);
+        assert_eq!(sb.inode_bitmap_start, 2);
+        assert_eq!(sb.inode_bitmap_blocks, 1);
+        assert_eq!(sb.feature_flags, FEATURE_WIDE_INODES | FEATURE_DIR_RECORDS);
+    }
+
+    #[test]
+    fn extents_are_recorded_as_a_feature() {
+        let options = FormatOptions::new();
+
+        assert!(!options
+            .clone()
+            .layout(TEST_MAGIC)
+            .unwrap()
+            .has_feature(FEATURE_EXTENTS));
+        assert!(options
+            .with_extents(true)
+            .layout(TEST_MAGIC)
+            .unwrap()
+            .has_feature(FEATURE_EXTENTS));
+    }
+
+    #[test]
+    fn large_volumes_span_multiple_bitmap_blocks() {
+        // A 100 GiB device.
+        let sb = FormatOptions::new()
+            .with_total_blocks(26_214_400)
+            .with_bytes_per_inode(64 * 1024)
+            .layout(TEST_MAGIC)
+            .unwrap();
+
+        assert_eq!(sb.data_bitmap_blocks, 800);
+        assert_eq!(sb.inodes_count, 1_638_400);
+        assert_eq!(sb.inode_bitmap_start, 801);
+        assert_eq!(sb.inode_bitmap_blocks, 50);
+        assert_eq!(sb.inode_table_start, 851);
+        assert_eq!(sb.data_region_start, 851 + 102_400);
+    }
+
+    #[test]
+    fn inode_count_rounds_up_to_whole_blocks() {
+        let sb = FormatOptions::new()
+            .with_total_blocks(128)
+            .with_inode_count(17)
+            .layout(TEST_MAGIC)
+            .unwrap();
+
+        assert_eq!(sb.inodes_count, 32);
+        assert_eq!(sb.inode_table_blocks, 2);
+        assert_eq!(sb.blocks_count, 128 - 5);
+    }
+
+    #[test]
+    fn bytes_per_inode_scales_with_device_size() {
+        let sb = FormatOptions::new()
+            .with_total_blocks(1024)
+            .with_bytes_per_inode(16 * 1024)
+            .layout(TEST_MAGIC)
+            .unwrap();
+
+        assert_eq!(sb.inodes_count, 256);
+        assert_eq!(sb.inode_table_blocks, 16);
+    }
+
+    #[test]
+    fn too_few_blocks_for_layout_returns_error() {
+        let result = FormatOptions::new()
+            .with_total_blocks(16)
+            .with_inode_count(320)
+            .layout(TEST_MAGIC);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reserved_blocks_must_leave_room_for_root() {
+        let options = FormatOptions::new().with_total_blocks(64);
+
+        assert!(options
+            .clone()
+            .with_reserved_blocks(55)
+            .layout(TEST_MAGIC)
+            .is_ok());
+        assert!(options.with_reserved_blocks(56).layout(TEST_MAGIC).is_err());
+    }
+}
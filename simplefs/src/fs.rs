@@ -1,49 +1,124 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::alloc::{Bitmap, NextAvailableAllocation};
+use crate::alloc::{Bitmap, ContiguousAllocation, NextAvailableAllocation};
+use crate::clock::{Clock, TimeUpdate};
+use crate::cred::{Credentials, EXECUTE, READ, WRITE};
+use crate::dir::{self, DirEntry, ReadDir};
+use crate::extent::{self, Extent, EXTENTS_PER_BLOCK, INLINE_EXTENTS};
+use crate::format::FormatOptions;
+use crate::handle::{FileDescriptor, HandleTable, OpenFile};
+use crate::htree::{self, IndexEntry, IndexPath, IndexRoot};
 use crate::io::BlockStorage;
-use crate::node::InodeGroup;
-use crate::sb::SuperBlock;
+use crate::metadata::{FileType, Metadata};
+use crate::mount::{AtimePolicy, MountOptions};
+use crate::node::{
+    BlockPath, Inode, InodeGroup, Timestamp, DIRECT_BLOCKS, DIR_INDEX_FLAG, EXTENTS_FLAG,
+    EXTENT_INDEX_FLAG, HOLE, INLINE_DATA_FLAG, INLINE_DATA_LEN, POINTERS_PER_BLOCK, SETGID, SETUID,
+};
+use crate::sb::{SuperBlock, FEATURE_DIR_RECORDS, FEATURE_EXTENTS, FEATURE_WIDE_INODES};
 
 use std::collections::HashMap;
-use std::ffi::OsString;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::io::SeekFrom;
+use std::ops::{BitOr, BitOrAssign, Range};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::sync::Arc;
 use thiserror::Error;
+use zerocopy::AsBytes;
 
 const SB_MAGIC: u32 = 0x5346_5342; // SFSB
 
 pub const BLOCK_SIZE: usize = 4096;
-const NODE_SIZE: usize = 256;
+/// Files are limited to the blocks reachable through the direct and indirect pointers of an inode.
+const MAX_FILE_SIZE: usize = (DIRECT_BLOCKS
+    + POINTERS_PER_BLOCK
+    + POINTERS_PER_BLOCK * POINTERS_PER_BLOCK
+    + POINTERS_PER_BLOCK * POINTERS_PER_BLOCK * POINTERS_PER_BLOCK)
+    * BLOCK_SIZE;
+
+/// The most symbolic links followed resolving a single path, like Linux's `MAXSYMLINKS`.
+const MAX_SYMLINKS: usize = 40;
+/// The longest path accepted counting the NUL terminating it in C, like Linux's `PATH_MAX`.
+const MAX_PATH_LEN: usize = 4096;
+
+/// Access times older than this are refreshed by reads under `AtimePolicy::Relatime`.
+const RELATIME_INTERVAL_SECS: u64 = 24 * 60 * 60;
 
 /// Known locations.
 const SUPERBLOCK_INDEX: usize = 0;
-const DATA_REGION_BMP: usize = 1;
-const INODE_BMP: usize = 2;
-const INODE_START: usize = 3;
-
-impl Default for SuperBlock {
-    fn default() -> Self {
-        let mut sb = SuperBlock::new();
-        sb.sb_magic = SB_MAGIC;
-        // This is a limited implementation only supporting at most 80 file system
-        // objects (files or directories).
-        sb.inodes_count = 5 * (BLOCK_SIZE / NODE_SIZE) as u32;
-        // Use the remaining space for user data blocks.
-        sb.blocks_count = 56;
-        sb.reserved_blocks_count = 0;
-        sb.free_blocks_count = 0;
-        // All inodes are initially free.
-        sb.free_inodes_count = sb.inodes_count;
-        sb
+
+// Encodes open filesystem call options http://man7.org/linux/man-pages/man2/open.2.html.
+//
+// Like the `O_*` flags, exactly one of the access modes (RO, WO, RW) applies and the remaining
+// options are combined with it, e.g. `OpenMode::CREATE | OpenMode::RW`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenMode(u32);
+
+impl OpenMode {
+    /// Open for reading only, the default when no access mode is given.
+    pub const RO: OpenMode = OpenMode(0);
+    /// Open for writing only.
+    pub const WO: OpenMode = OpenMode(0o1);
+    /// Open for reading and writing.
+    pub const RW: OpenMode = OpenMode(0o2);
+    /// Create a regular file at the path if nothing exists there yet.
+    pub const CREATE: OpenMode = OpenMode(0o100);
+    /// Combined with CREATE, fail if something already exists at the path.
+    pub const EXCL: OpenMode = OpenMode(0o200);
+    /// Discard the existing contents of a regular file opened for writing.
+    pub const TRUNC: OpenMode = OpenMode(0o1000);
+    /// Every write is made at the end of the file regardless of the current position.
+    pub const APPEND: OpenMode = OpenMode(0o2000);
+    /// Fail unless the path refers to a directory.
+    pub const DIRECTORY: OpenMode = OpenMode(0o200_000);
+    /// Fail if the path names a symbolic link rather than following it. A trailing slash still
+    /// follows the link, the path then names the directory it points at.
+    pub const NOFOLLOW: OpenMode = OpenMode(0o400_000);
+
+    const ACCESS_MASK: u32 = 0o3;
+
+    /// Returns true if all of the options set in `other` are also set.
+    pub fn contains(self, other: OpenMode) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether a descriptor opened with these options may be read from.
+    pub fn is_readable(self) -> bool {
+        self.0 & Self::ACCESS_MASK != Self::WO.0
+    }
+
+    /// Whether a descriptor opened with these options may be written to.
+    pub fn is_writable(self) -> bool {
+        let access = self.0 & Self::ACCESS_MASK;
+        access == Self::WO.0 || access == Self::RW.0
+    }
+
+    /// The permissions needed on an existing file to open it with these options.
+    fn required_access(self) -> u16 {
+        let mut access = 0;
+        if self.is_readable() {
+            access |= READ;
+        }
+        if self.is_writable() {
+            access |= WRITE;
+        }
+        access
     }
 }
 
-// Encodes open filesystem call options http://man7.org/linux/man-pages/man2/open.2.html.
-pub enum OpenMode {
-    RO,
-    WO,
-    RW,
-    DIRECTORY,
-    CREATE,
+impl BitOr for OpenMode {
+    type Output = OpenMode;
+
+    fn bitor(self, rhs: OpenMode) -> OpenMode {
+        OpenMode(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for OpenMode {
+    fn bitor_assign(&mut self, rhs: OpenMode) {
+        self.0 |= rhs.0;
+    }
 }
 
 #[derive(Error, Debug)]
@@ -54,298 +129,4954 @@ pub enum SFSError {
     DoesNotExist,
     #[error("invalid file system block layout")]
     InvalidBlock(#[from] std::io::Error),
+    #[error("no free data blocks or inodes left on device")]
+    NoSpace,
+    #[error("write exceeds the maximum file size")]
+    FileTooLarge,
+    #[error("bad file descriptor")]
+    BadDescriptor,
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("is a directory")]
+    IsADirectory,
+    #[error("file already exists")]
+    AlreadyExists,
+    #[error("directory not empty")]
+    DirectoryNotEmpty,
+    #[error("directory contents are corrupt")]
+    CorruptDirectory,
+    #[error("file name too long")]
+    NameTooLong,
+    #[error("operation not permitted")]
+    NotPermitted,
+    #[error("too many links")]
+    TooManyLinks,
+    #[error("too many levels of symbolic links")]
+    SymlinkLoop,
+    #[error("no data or hole at or past the offset")]
+    NoSuchOffset,
+    #[error("permission denied")]
+    PermissionDenied,
 }
 
-/// A fixed 64 4k block file system. Currently hard coded for simplicity with
-/// one super block, one inode bitmap, one data block bitmap, five inode blocks,
-/// and 56 blocks for data storage.
+/// A 4k block file system made up of one super block, a data block bitmap, an inode bitmap, an
+/// inode table and a data region. The size of each region is chosen when the file system is
+/// formatted, see `FormatOptions`.
+///
+/// Metadata changes are made in memory and written back to the device by `sync`, which also runs
+/// when the file system is dropped.
 pub struct SFS<T: BlockStorage> {
     dev: T,
     super_block: SuperBlock,
     data_map: Bitmap,
     inodes: InodeGroup,
+    handles: HandleTable,
+    /// The directory relative paths are resolved from, see `chdir`.
+    cwd: u32,
+    /// The identity calls are checked against, see `set_credentials`.
+    creds: Credentials,
+    /// Whether the superblock changed since it was last written to disk.
+    dirty_super_block: bool,
+    /// The source of the timestamps recorded in inodes.
+    clock: Arc<dyn Clock>,
+    atime: AtimePolicy,
 }
 
 impl<T: BlockStorage> SFS<T> {
-    /// Initializes the file system onto owned block storage.
-    ///
-    /// # Layout
-    /// ==============================================================================
-    /// | SuperBlock | Bitmap (data region) | Bitmap (inodes) | Inodes | Data Region |
-    /// ==============================================================================
-    pub fn create(mut dev: T) -> Result<Self, SFSError> {
-        // Reusable buffer for writing blocks.
-        let mut block_buffer = [0; 4096];
-
-        // Init SuperBlock header.
-        let super_block = SuperBlock::default();
-        block_buffer[0..28].copy_from_slice(super_block.serialize());
-        dev.write_block(SUPERBLOCK_INDEX, &mut block_buffer)?;
-
-        // Init allocation map for data region.
-        let data_map = Bitmap::new();
-        block_buffer.copy_from_slice(data_map.serialize());
-        dev.write_block(DATA_REGION_BMP, &mut block_buffer)?;
-
-        // Initialize inode structure with root node.
-        let inodes = InodeGroup::new(Bitmap::new());
-        block_buffer.copy_from_slice(inodes.allocations().serialize());
-        dev.write_block(INODE_BMP, &mut block_buffer)?;
-        dev.write_block(INODE_START, &mut inodes.serialize_block(0))?;
-        dev.sync_disk()?;
-
-        Ok(SFS {
+    /// Initializes the file system onto owned block storage using the default `FormatOptions`.
+    pub fn create(dev: T) -> Result<Self, SFSError> {
+        Self::format(dev, &FormatOptions::default())
+    }
+
+    /// Initializes a file system with the geometry described by `options` onto owned block
+    /// storage. The layout is recorded in the superblock so it can be mounted again with
+    /// `from_block_storage`.
+    pub fn format(dev: T, options: &FormatOptions) -> Result<Self, SFSError> {
+        Self::format_and_mount(dev, options, &MountOptions::default())
+    }
+
+    /// Initializes a file system like `format`, leaving it mounted with `mount_options`.
+    pub fn format_and_mount(
+        dev: T,
+        options: &FormatOptions,
+        mount_options: &MountOptions,
+    ) -> Result<Self, SFSError> {
+        let super_block = options.layout(SB_MAGIC)?;
+
+        // Init allocation map for data region. The map tracks absolute block numbers so the blocks
+        // holding file system metadata are reserved up front and never handed out for data.
+        let mut data_map = Bitmap::new(super_block.data_bitmap_blocks);
+        for block in 0..super_block.data_region_start {
+            data_map.set_reserved(block as usize);
+        }
+
+        // Initialize inode structure with root node, the root is its own parent.
+        let mut fs = SFS {
             dev,
-            inodes,
+            inodes: InodeGroup::new(
+                Bitmap::new(super_block.inode_bitmap_blocks),
+                super_block.inodes_count,
+                Self::node_flags(&super_block),
+            ),
             data_map,
             super_block,
-        })
+            handles: HandleTable::new(),
+            cwd: 0,
+            creds: Credentials::default(),
+            dirty_super_block: true,
+            clock: Arc::clone(&mount_options.clock),
+            atime: mount_options.atime,
+        };
+        fs.set_credentials(mount_options.creds.clone())?;
+        fs.init_times(0);
+        fs.super_block.free_inodes_count -= 1;
+        fs.write_dir(0, vec![(OsString::from("."), 0), (OsString::from(".."), 0)])?;
+
+        fs.sync()?;
+        Ok(fs)
     }
 
-    pub fn from_block_storage(mut dev: T) -> Result<Self, SFSError> {
+    /// Mounts a file system previously initialized by `create` or `format` with the default
+    /// `MountOptions`.
+    pub fn from_block_storage(dev: T) -> Result<Self, SFSError> {
+        Self::mount(dev, &MountOptions::default())
+    }
+
+    /// Mounts a file system previously initialized by `create` or `format`.
+    pub fn mount(mut dev: T, options: &MountOptions) -> Result<Self, SFSError> {
         let mut block_buf = vec![0; 4096];
 
         // Read superblock from first block;
         dev.read_block(SUPERBLOCK_INDEX, &mut block_buf)?;
-        let super_block = SuperBlock::parse(&block_buf, SB_MAGIC);
+        let mut super_block = SuperBlock::parse(&block_buf, SB_MAGIC);
+        let legacy_layout = super_block.inode_table_blocks == 0;
+        if legacy_layout {
+            // Images formatted before the layout was recorded use the original fixed geometry.
+            super_block.inode_table_start = 3;
+            super_block.inode_table_blocks = 5;
+            super_block.data_region_start = 8;
+            super_block.total_blocks_count = 8 + super_block.blocks_count;
+        }
+        if super_block.data_bitmap_blocks == 0 {
+            // Images formatted before bitmaps could span several blocks hold one of each.
+            super_block.data_bitmap_start = 1;
+            super_block.data_bitmap_blocks = 1;
+            super_block.inode_bitmap_start = 2;
+            super_block.inode_bitmap_blocks = 1;
+        }
 
-        dev.read_block(DATA_REGION_BMP, &mut block_buf)?;
-        let data_map = Bitmap::parse(&block_buf);
+        let mut data_map = Self::load_bitmap(
+            &mut dev,
+            super_block.data_bitmap_start,
+            super_block.data_bitmap_blocks,
+        )?;
+        let inode_allocs = Self::load_bitmap(
+            &mut dev,
+            super_block.inode_bitmap_start,
+            super_block.inode_bitmap_blocks,
+        )?;
+        if legacy_layout {
+            // These images neither reserved the metadata blocks in the data bitmap nor kept the free
+            // counts, both are rebuilt from the bitmaps.
+            for block in 0..super_block.data_region_start {
+                data_map.set_reserved(block as usize);
+            }
+            super_block.free_blocks_count = data_map.count_free(
+                super_block.data_region_start as usize..super_block.total_blocks_count as usize,
+            ) as u32;
+            super_block.free_inodes_count =
+                inode_allocs.count_free(0..super_block.inodes_count as usize) as u32;
+        }
+        let mut inodes = InodeGroup::open(
+            inode_allocs,
+            super_block.inodes_count,
+            Self::node_flags(&super_block),
+        );
 
-        dev.read_block(INODE_BMP, &mut block_buf)?;
-        let inode_allocs = Bitmap::parse(&block_buf);
-        let mut inodes = InodeGroup::open(inode_allocs);
+        for i in 0..super_block.inode_table_blocks {
+            dev.read_block((super_block.inode_table_start + i) as usize, &mut block_buf)?;
+            inodes.load_block(i, &block_buf);
+        }
 
-        for i in INODE_START..INODE_START + 5 {
-            dev.read_block(i, &mut block_buf)?;
-            // TODO(allancalix): This is a bit ugly. Because the inode group is unaware that's first
-            // disk block is at an offset (INODE_START) we have to subtract the offset before loading
-            // the block.
-            inodes.load_block((i - INODE_START) as u32, &block_buf);
+        // Older images are upgraded to wide inodes, written back on the next sync.
+        let upgrade = !super_block.has_feature(FEATURE_WIDE_INODES);
+        if upgrade {
+            inodes.upgrade_legacy_nodes();
+            super_block.feature_flags |= FEATURE_WIDE_INODES;
         }
 
-        Ok(SFS {
+        let mut fs = SFS {
             dev,
             inodes,
             data_map,
             super_block,
-        })
+            handles: HandleTable::new(),
+            cwd: 0,
+            creds: Credentials::default(),
+            dirty_super_block: upgrade || legacy_layout,
+            clock: Arc::clone(&options.clock),
+            atime: options.atime,
+        };
+        fs.set_credentials(options.creds.clone())?;
+        if !fs.super_block.has_feature(FEATURE_DIR_RECORDS) {
+            fs.upgrade_legacy_dirs()?;
+            fs.super_block.feature_flags |= FEATURE_DIR_RECORDS;
+            fs.dirty_super_block = true;
+        }
+        Ok(fs)
     }
 
-    /// Opens a file descriptor at the path provided. By default, this implementation will return an
-    /// error if the file does not exists. Set OpenMode to override the behavior and create a file or
-    /// directory.
-    pub fn open<P: AsRef<Path>>(&mut self, path: P, mode: OpenMode) -> Result<u32, SFSError> {
-        let mut parts = path.as_ref().components();
-        if Some(std::path::Component::RootDir) != parts.next() {
-            return Err(SFSError::InvalidArgument(
-                "path must start with \"/\"".to_string(),
-            ));
+    /// Writes every metadata change made since the last sync (the superblock, allocation bitmaps
+    /// and modified inode blocks) to the device and flushes it.
+    pub fn sync(&mut self) -> Result<(), SFSError> {
+        let mut block_buf = [0; BLOCK_SIZE];
+
+        if self.dirty_super_block {
+            let sb = self.super_block.serialize();
+            block_buf[..sb.len()].copy_from_slice(sb);
+            self.dev.write_block(SUPERBLOCK_INDEX, &mut block_buf)?;
+            self.dirty_super_block = false;
+        }
+
+        for block in self.data_map.take_dirty_blocks() {
+            block_buf.copy_from_slice(self.data_map.serialize_block(block));
+            self.dev.write_block(
+                (self.super_block.data_bitmap_start + block) as usize,
+                &mut block_buf,
+            )?;
+        }
+
+        for block in self.inodes.take_dirty_allocations() {
+            block_buf.copy_from_slice(self.inodes.allocations().serialize_block(block));
+            self.dev.write_block(
+                (self.super_block.inode_bitmap_start + block) as usize,
+                &mut block_buf,
+            )?;
+        }
+        for block in self.inodes.take_dirty_blocks() {
+            self.dev.write_block(
+                (self.super_block.inode_table_start + block) as usize,
+                &mut self.inodes.serialize_block(block),
+            )?;
+        }
+
+        self.dev.sync_disk()?;
+        Ok(())
+    }
+
+    /// The flags new inodes start with given the features the file system was formatted with.
+    fn node_flags(super_block: &SuperBlock) -> u32 {
+        if super_block.has_feature(FEATURE_EXTENTS) {
+            EXTENTS_FLAG
+        } else {
+            0
+        }
+    }
+
+    /// Reads a bitmap spanning `blocks` disk blocks starting at `start`.
+    fn load_bitmap(dev: &mut T, start: u32, blocks: u32) -> Result<Bitmap, SFSError> {
+        let mut block_buf = vec![0; BLOCK_SIZE];
+        let mut bitmap = Bitmap::new(blocks);
+        for block in 0..blocks {
+            dev.read_block((start + block) as usize, &mut block_buf)?;
+            bitmap.load_block(block, &block_buf);
         }
+        Ok(bitmap)
+    }
+
+    /// Opens a file descriptor at the path provided. By default, this implementation will return an
+    /// error if the file does not exists. Set `OpenMode::CREATE` to override the behavior and create
+    /// a regular file, combined with an access mode granting the descriptor read and/or write access.
+    ///
+    /// Relative paths, like those of every other call taking a path, are resolved from the working
+    /// directory, see `chdir`.
+    pub fn open<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mode: OpenMode,
+    ) -> Result<FileDescriptor, SFSError> {
+        self.open_in(self.cwd, path.as_ref(), mode)
+    }
+
+    /// Opens a file like `open`, resolving a relative path from the directory `dirfd` refers to
+    /// instead of the working directory.
+    pub fn openat<P: AsRef<Path>>(
+        &mut self,
+        dirfd: FileDescriptor,
+        path: P,
+        mode: OpenMode,
+    ) -> Result<FileDescriptor, SFSError> {
+        let dir = self.dir_fd(dirfd)?;
+        self.open_in(dir, path.as_ref(), mode)
+    }
+
+    fn open_in(
+        &mut self,
+        dir: u32,
+        path: &Path,
+        mode: OpenMode,
+    ) -> Result<FileDescriptor, SFSError> {
+        let (parent, existing) = match self.resolve_parent(dir, path, true)? {
+            Some((parent, name)) => {
+                let existing = self.lookup(parent, &name)?;
+                (parent, existing.ok_or(name))
+            }
+            // The root directory has no parent to create it in.
+            None => (0, Ok(0)),
+        };
 
-        let mut inum = 0;
-        while let Some(part) = parts.next() {
-            let content = self.read_dir(inum)?;
-            let node = content.get(part.as_os_str());
-            if node.is_none() {
-                if parts.peekable().peek().is_some() {
+        let inum = match existing {
+            Ok(inum) => {
+                if mode.contains(OpenMode::CREATE | OpenMode::EXCL) {
+                    return Err(SFSError::AlreadyExists);
+                }
+                if mode.contains(OpenMode::NOFOLLOW)
+                    && !has_trailing_slash(path)
+                    && self.is_symlink(inum)
+                {
+                    return Err(SFSError::SymlinkLoop);
+                }
+                let inum = self.follow(parent, inum, &mut 0)?;
+                self.check_access(inum, mode.required_access())?;
+                inum
+            }
+            Err(name) => {
+                if !mode.contains(OpenMode::CREATE) {
+                    return Err(SFSError::DoesNotExist);
+                }
+                if has_trailing_slash(path) {
+                    return Err(SFSError::IsADirectory);
+                }
+                if mode.contains(OpenMode::DIRECTORY) {
                     return Err(SFSError::InvalidArgument(
-                        "Missing subdirectory in path.".to_string(),
+                        "open can not create directories".to_string(),
                     ));
                 }
 
-                match mode {
-                    OpenMode::CREATE => break,
-                    _ => return Err(SFSError::DoesNotExist),
-                }
+                self.check_access(parent, WRITE)?;
+
+                let created_file = self.new_inode(InodeGroup::new_file, parent)?;
+                self.insert_entry(parent, name, created_file)?;
+                created_file
             }
+        };
 
-            inum = *node.unwrap();
+        let is_dir = self.inodes.get(inum).unwrap().is_dir();
+        if mode.contains(OpenMode::DIRECTORY) && !is_dir {
+            return Err(SFSError::NotADirectory);
+        }
+        if mode.is_writable() && is_dir {
+            return Err(SFSError::IsADirectory);
+        }
+        if mode.contains(OpenMode::TRUNC) && mode.is_writable() {
+            self.release_blocks(inum)?;
+            self.mark_modified(inum);
         }
 
-        match mode {
-            OpenMode::CREATE => {
-                let created_file = self.inodes.new_file();
-                let mut parent_dir = self.read_dir(inum)?;
-                parent_dir.insert(
-                    OsString::from(path.as_ref().file_name().unwrap()),
-                    created_file,
-                );
-                self.write_dir(inum, parent_dir)?;
-                Ok(created_file)
-            }
-            OpenMode::RO => Ok(inum),
-            // The rest of the modes.
-            _ => unimplemented!(),
+        Ok(self.handles.insert(OpenFile::new(inum, mode)))
+    }
+
+    /// Creates an empty directory at the path provided. The parent directory must already exist.
+    pub fn mkdir<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SFSError> {
+        self.mkdir_in(self.cwd, path.as_ref())
+    }
+
+    /// Creates a directory like `mkdir`, resolving a relative path from the directory `dirfd`
+    /// refers to.
+    pub fn mkdirat<P: AsRef<Path>>(
+        &mut self,
+        dirfd: FileDescriptor,
+        path: P,
+    ) -> Result<(), SFSError> {
+        let dir = self.dir_fd(dirfd)?;
+        self.mkdir_in(dir, path.as_ref())
+    }
+
+    fn mkdir_in(&mut self, dir: u32, path: &Path) -> Result<(), SFSError> {
+        let (parent, name) = self
+            .resolve_parent(dir, path, true)?
+            .ok_or(SFSError::AlreadyExists)?;
+        if self.lookup(parent, &name)?.is_some() {
+            return Err(SFSError::AlreadyExists);
         }
+        self.check_access(parent, WRITE)?;
+        // The new directory's ".." entry will link back to the parent.
+        if self.inodes.get(parent).unwrap().links_count == std::u16::MAX {
+            return Err(SFSError::TooManyLinks);
+        }
+
+        let created_dir = self.new_inode(InodeGroup::new_dir, parent)?;
+        let filled = self
+            .write_dir(
+                created_dir,
+                vec![
+                    (OsString::from("."), created_dir),
+                    (OsString::from(".."), parent),
+                ],
+            )
+            .and_then(|()| self.insert_entry(parent, name, created_dir));
+        if let Err(e) = filled {
+            self.free_inode(created_dir)?;
+            return Err(e);
+        }
+        self.inodes.get_mut(parent).unwrap().links_count += 1;
+        Ok(())
     }
 
-    fn write_dir(&mut self, dir: u32, entries: HashMap<OsString, u32>) -> Result<(), SFSError> {
-        let mut contents: String = entries
-            .iter()
-            .map(|(k, v)| format!("{}:{}\n", v, k.to_str().unwrap()))
-            .collect();
-        contents.push('\0');
+    /// Removes the directory at the path provided. Only empty directories, those holding nothing
+    /// but their "." and ".." entries, can be removed.
+    pub fn rmdir<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SFSError> {
+        let (parent, name) = self
+            .resolve_parent(self.cwd, path.as_ref(), true)?
+            .ok_or_else(|| {
+                SFSError::InvalidArgument("can not remove the root directory".to_string())
+            })?;
+        if name == "." || name == ".." {
+            return Err(SFSError::InvalidArgument(
+                "can not remove \".\" or \"..\"".to_string(),
+            ));
+        }
 
-        let node = self.inodes.get_mut(dir).unwrap();
-        let allocated_blocks: Vec<u32> = node
-            .blocks
-            .iter()
-            .filter(|block| *block > &8_u32)
-            .copied()
-            .collect();
+        let dir = self.lookup(parent, &name)?.ok_or(SFSError::DoesNotExist)?;
+        if !self.inodes.get(dir).unwrap().is_dir() {
+            return Err(SFSError::NotADirectory);
+        }
+        self.check_remove(parent, dir)?;
+        if !self.is_empty_dir(dir)? {
+            return Err(SFSError::DirectoryNotEmpty);
+        }
 
-        if allocated_blocks.len() < 1 + (contents.as_bytes().len() / BLOCK_SIZE) {
-            let needed = 1 + (contents.as_bytes().len() / BLOCK_SIZE);
-            let have = allocated_blocks.len();
-
-            let mut alloc_gen = NextAvailableAllocation::new(self.data_map, None);
-            let new_blocks: Vec<u32> = (0..(needed - have))
-                // Panics if no free blocks are available.
-                .map(|_| alloc_gen.next().unwrap() as u32)
-                .collect();
-            // Mark new blocks as allocated.
-            for &new_block in new_blocks.iter() {
-                self.data_map.set_reserved(new_block as usize);
-            }
-            let mut all_blocks = allocated_blocks.iter().chain(new_blocks.iter());
-            let new_blocks = all_blocks.clone().copied().collect::<Vec<u32>>();
-            node.blocks[0..new_blocks.len()].copy_from_slice(&new_blocks);
-
-            unsafe {
-                contents
-                    .as_bytes_mut()
-                    .chunks_mut(BLOCK_SIZE)
-                    .for_each(|chunk| {
-                        self.dev
-                            .write_block(*all_blocks.next().unwrap() as usize, chunk)
-                            .unwrap();
-                    });
-            }
-            return Ok(());
+        self.remove_entry(parent, &name)?;
+        self.inodes.get_mut(parent).unwrap().links_count -= 1;
+
+        self.drop_link(dir)
+    }
+
+    /// Removes the directory entry at the path provided. The file's inode and data blocks are
+    /// freed once no directory entries link to it and it is no longer open, so descriptors opened
+    /// before the unlink remain usable until they are closed.
+    pub fn unlink<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SFSError> {
+        self.unlink_in(self.cwd, path.as_ref())
+    }
+
+    /// Removes a directory entry like `unlink`, resolving a relative path from the directory
+    /// `dirfd` refers to.
+    pub fn unlinkat<P: AsRef<Path>>(
+        &mut self,
+        dirfd: FileDescriptor,
+        path: P,
+    ) -> Result<(), SFSError> {
+        let dir = self.dir_fd(dirfd)?;
+        self.unlink_in(dir, path.as_ref())
+    }
+
+    fn unlink_in(&mut self, dir: u32, path: &Path) -> Result<(), SFSError> {
+        let (parent, name) = self
+            .resolve_parent(dir, path, true)?
+            .ok_or(SFSError::IsADirectory)?;
+
+        let inum = self.lookup(parent, &name)?.ok_or(SFSError::DoesNotExist)?;
+        if self.inodes.get(inum).unwrap().is_dir() {
+            return Err(SFSError::IsADirectory);
+        }
+        self.check_remove(parent, inum)?;
+
+        self.remove_entry(parent, &name)?;
+
+        self.drop_link(inum)
+    }
+
+    /// Creates a symbolic link at `linkpath` pointing at `target`. The target is stored as given and
+    /// only resolved when the link is followed, relative targets from the directory holding the
+    /// link. Targets of up to 64 bytes are kept in the inode itself, longer ones, up to a block,
+    /// in a data block.
+    pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        target: P,
+        linkpath: Q,
+    ) -> Result<(), SFSError> {
+        self.symlink_in(target.as_ref(), self.cwd, linkpath.as_ref())
+    }
+
+    /// Creates a symbolic link like `symlink`, resolving a relative `linkpath` from the directory
+    /// `dirfd` refers to. The target is stored as given either way.
+    pub fn symlinkat<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        target: P,
+        dirfd: FileDescriptor,
+        linkpath: Q,
+    ) -> Result<(), SFSError> {
+        let dir = self.dir_fd(dirfd)?;
+        self.symlink_in(target.as_ref(), dir, linkpath.as_ref())
+    }
+
+    fn symlink_in(&mut self, target: &Path, dir: u32, linkpath: &Path) -> Result<(), SFSError> {
+        let target = target.as_os_str().as_bytes();
+        if target.is_empty() || target.contains(&0) {
+            return Err(SFSError::InvalidArgument(
+                "symbolic link targets must be non-empty and free of NUL bytes".to_string(),
+            ));
+        }
+        if target.len() > BLOCK_SIZE {
+            return Err(SFSError::NameTooLong);
+        }
+        let (parent, name) = self
+            .resolve_parent(dir, linkpath, false)?
+            .ok_or(SFSError::AlreadyExists)?;
+        if self.lookup(parent, &name)?.is_some() {
+            return Err(SFSError::AlreadyExists);
         }
+        self.check_access(parent, WRITE)?;
 
-        info!("Writing content \"{}\" to dir inode {}.", contents, dir);
-        let mut blocks = allocated_blocks.iter();
-        unsafe {
-            contents
-                .as_bytes_mut()
-                .chunks_mut(BLOCK_SIZE)
-                .for_each(|chunk| {
-                    self.dev
-                        .write_block(*blocks.next().unwrap() as usize, chunk)
-                        .unwrap();
-                });
+        let link = self.new_inode(InodeGroup::new_symlink, parent)?;
+        // A target no longer than a block is stored whole or not at all.
+        let stored = if target.len() <= INLINE_DATA_LEN {
+            self.inodes.get_mut(link).unwrap().set_inline_data(target);
+            Ok(())
+        } else {
+            self.write_inode(link, 0, target).map(|_| ())
+        };
+        if let Err(e) = stored.and_then(|()| self.insert_entry(parent, name, link)) {
+            self.free_inode(link)?;
+            return Err(e);
         }
         Ok(())
     }
 
-    fn read_dir(&mut self, inum: u32) -> Result<HashMap<OsString, u32>, SFSError> {
-        let content = self.read_file(inum)?;
-        let contents_parsed = String::from_utf8(content).unwrap();
+    /// Returns the target of the symbolic link at the path provided.
+    pub fn readlink<P: AsRef<Path>>(&mut self, path: P) -> Result<PathBuf, SFSError> {
+        self.readlink_in(self.cwd, path.as_ref())
+    }
+
+    /// Returns the target of a symbolic link like `readlink`, resolving a relative path from the
+    /// directory `dirfd` refers to.
+    pub fn readlinkat<P: AsRef<Path>>(
+        &mut self,
+        dirfd: FileDescriptor,
+        path: P,
+    ) -> Result<PathBuf, SFSError> {
+        let dir = self.dir_fd(dirfd)?;
+        self.readlink_in(dir, path.as_ref())
+    }
 
-        let mut dir_contents = HashMap::new();
-        for line in contents_parsed.lines() {
-            let mut contents = line.split(':');
-            let entry_inum = contents.next().unwrap().parse::<u32>().unwrap();
-            let entry_name = OsString::from(contents.next().unwrap());
-            dir_contents.insert(entry_name, entry_inum);
+    fn readlink_in(&mut self, dir: u32, path: &Path) -> Result<PathBuf, SFSError> {
+        let inum = self.resolve_nofollow(dir, path)?;
+        if !self.is_symlink(inum) {
+            return Err(SFSError::InvalidArgument("not a symbolic link".to_string()));
         }
 
-        Ok(dir_contents)
+        let target = self.read_link(inum)?;
+        self.mark_accessed(inum);
+        Ok(PathBuf::from(OsString::from_vec(target)))
     }
 
-    fn read_file(&mut self, inum: u32) -> Result<Vec<u8>, SFSError> {
-        let node = self.inodes.get(inum);
-        if node.is_none() {
-            return Err(SFSError::DoesNotExist);
+    /// Gives the file at `existing` the additional name `new`, both names linking to the same
+    /// inode. The inode counts its links and is only freed once every name has been unlinked.
+    /// Directories can not be linked, they only ever have the one name in their parent.
+    pub fn link<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        existing: P,
+        new: Q,
+    ) -> Result<(), SFSError> {
+        self.link_in(self.cwd, existing.as_ref(), self.cwd, new.as_ref())
+    }
+
+    /// Creates a hard link like `link`, resolving a relative `existing` path from the directory
+    /// `olddirfd` refers to and a relative `new` path from the directory `newdirfd` refers to.
+    pub fn linkat<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        olddirfd: FileDescriptor,
+        existing: P,
+        newdirfd: FileDescriptor,
+        new: Q,
+    ) -> Result<(), SFSError> {
+        let old_dir = self.dir_fd(olddirfd)?;
+        let new_dir = self.dir_fd(newdirfd)?;
+        self.link_in(old_dir, existing.as_ref(), new_dir, new.as_ref())
+    }
+
+    fn link_in(
+        &mut self,
+        old_dir: u32,
+        existing: &Path,
+        new_dir: u32,
+        new: &Path,
+    ) -> Result<(), SFSError> {
+        let inum = self.resolve_nofollow(old_dir, existing)?;
+        let node = self.inodes.get(inum).unwrap();
+        if node.is_dir() {
+            return Err(SFSError::NotPermitted);
+        }
+        if node.links_count == std::u16::MAX {
+            return Err(SFSError::TooManyLinks);
         }
-        let allocated_blocks: Vec<u32> = node
-            .unwrap()
-            .blocks
-            .iter()
-            .filter(|block| *block > &(self.super_block.inodes_count + 3))
-            .copied()
-            .collect();
 
-        let mut content = vec![0; allocated_blocks.len()];
-        for (i, &block) in allocated_blocks.iter().enumerate() {
-            let start = i * BLOCK_SIZE;
-            let end = start + BLOCK_SIZE;
-            self.dev
-                .read_block(block as usize, &mut content[start..end])?;
+        let (parent, name) = self
+            .resolve_parent(new_dir, new, false)?
+            .ok_or(SFSError::AlreadyExists)?;
+        if self.lookup(parent, &name)?.is_some() {
+            return Err(SFSError::AlreadyExists);
         }
-        Ok(content)
+        self.check_access(parent, WRITE)?;
+
+        self.insert_entry(parent, name, inum)?;
+        self.inodes.get_mut(inum).unwrap().links_count += 1;
+        self.mark_changed(inum);
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::io::{FileBlockEmulator, FileBlockEmulatorBuilder};
+    /// Moves the file or directory at `from` to `to`, replacing whatever was linked at `to`. The
+    /// new name is linked before the old one is removed so the file is reachable throughout.
+    ///
+    /// An existing target must be of the same kind as the source and directories being replaced
+    /// must be empty. A directory can not be moved beneath itself, and moving one to another parent
+    /// takes write permission on it to update its ".." entry.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> Result<(), SFSError> {
+        self.rename_in(self.cwd, from.as_ref(), self.cwd, to.as_ref())
+    }
 
-    fn create_test_device() -> FileBlockEmulator {
-        let dev = tempfile::tempfile().unwrap();
-        FileBlockEmulatorBuilder::from(dev)
-            .with_block_size(64)
-            .build()
-            .expect("Could not initialize disk emulator.")
+    /// Moves a file or directory like `rename`, resolving a relative `from` path from the directory
+    /// `olddirfd` refers to and a relative `to` path from the directory `newdirfd` refers to.
+    pub fn renameat<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        olddirfd: FileDescriptor,
+        from: P,
+        newdirfd: FileDescriptor,
+        to: Q,
+    ) -> Result<(), SFSError> {
+        let old_dir = self.dir_fd(olddirfd)?;
+        let new_dir = self.dir_fd(newdirfd)?;
+        self.rename_in(old_dir, from.as_ref(), new_dir, to.as_ref())
     }
 
-    #[test]
-    fn root_dir_returns_root_fd() {
-        let dev = create_test_device();
-        let mut fs = SFS::create(dev).unwrap();
-        assert_eq!(fs.open("/", OpenMode::RO).unwrap(), 0);
+    fn rename_in(
+        &mut self,
+        old_dir: u32,
+        from: &Path,
+        new_dir: u32,
+        to: &Path,
+    ) -> Result<(), SFSError> {
+        let dots = || SFSError::InvalidArgument("can not rename \".\" or \"..\"".to_string());
+        let root = || SFSError::InvalidArgument("can not rename the root directory".to_string());
+
+        let (from_parent, from_name) =
+            self.resolve_parent(old_dir, from, true)?.ok_or_else(root)?;
+        if from_name == "." || from_name == ".." {
+            return Err(dots());
+        }
+        let inum = self
+            .lookup(from_parent, &from_name)?
+            .ok_or(SFSError::DoesNotExist)?;
+        let is_dir = self.inodes.get(inum).unwrap().is_dir();
+
+        // A directory may be moved to a new name ending in a slash, just as one may be created.
+        let (to_parent, to_name) = self.resolve_parent(new_dir, to, is_dir)?.ok_or_else(root)?;
+        if to_name == "." || to_name == ".." {
+            return Err(dots());
+        }
+        let replaced = self.lookup(to_parent, &to_name)?;
+        if replaced == Some(inum) {
+            return Ok(());
+        }
+        self.check_remove(from_parent, inum)?;
+        match replaced {
+            Some(replaced) => self.check_remove(to_parent, replaced)?,
+            None => self.check_access(to_parent, WRITE)?,
+        }
+        if is_dir && from_parent != to_parent {
+            // Moving a directory rewrites its ".." entry.
+            self.check_access(inum, WRITE)?;
+        }
+        if is_dir && self.is_ancestor(inum, to_parent)? {
+            return Err(SFSError::InvalidArgument(
+                "can not move a directory beneath itself".to_string(),
+            ));
+        }
+        if let Some(replaced) = replaced {
+            match (is_dir, self.inodes.get(replaced).unwrap().is_dir()) {
+                (true, false) => return Err(SFSError::NotADirectory),
+                (false, true) => return Err(SFSError::IsADirectory),
+                (true, true) if !self.is_empty_dir(replaced)? => {
+                    return Err(SFSError::DirectoryNotEmpty)
+                }
+                _ => (),
+            }
+        }
+        // A directory moved to a new parent adds a link to it, unless it takes the place of
+        // another directory.
+        if is_dir
+            && from_parent != to_parent
+            && replaced.is_none()
+            && self.inodes.get(to_parent).unwrap().links_count == std::u16::MAX
+        {
+            return Err(SFSError::TooManyLinks);
+        }
+
+        self.insert_entry(to_parent, to_name, inum)?;
+        self.remove_entry(from_parent, &from_name)?;
+
+        if let Some(replaced) = replaced {
+            if is_dir {
+                // The replaced directory's ".." entry no longer links to the target's parent.
+                self.inodes.get_mut(to_parent).unwrap().links_count -= 1;
+            }
+            self.drop_link(replaced)?;
+        }
+        if is_dir && from_parent != to_parent {
+            self.insert_entry(inum, OsString::from(".."), to_parent)?;
+            self.inodes.get_mut(from_parent).unwrap().links_count -= 1;
+            self.inodes.get_mut(to_parent).unwrap().links_count += 1;
+        }
+        self.mark_changed(inum);
+        Ok(())
     }
 
-    #[test]
-    fn file_not_found_without_create_returns_error() {
-        let dev = create_test_device();
-        let mut fs = SFS::create(dev).unwrap();
+    /// Returns information about the file at the path provided.
+    pub fn stat<P: AsRef<Path>>(&mut self, path: P) -> Result<Metadata, SFSError> {
+        let inum = self.resolve(self.cwd, path.as_ref())?;
+        Ok(Metadata::new(inum, self.inodes.get(inum).unwrap()))
+    }
 
-        let result = fs.open("/foo", OpenMode::RO);
-        match result.unwrap_err() {
-            SFSError::DoesNotExist => (),
-            _ => assert!(false, "Unexpected error type."),
+    /// Returns information about a file like `stat`, resolving a relative path from the directory
+    /// `dirfd` refers to.
+    pub fn fstatat<P: AsRef<Path>>(
+        &mut self,
+        dirfd: FileDescriptor,
+        path: P,
+    ) -> Result<Metadata, SFSError> {
+        let dir = self.dir_fd(dirfd)?;
+        let inum = self.resolve(dir, path.as_ref())?;
+        Ok(Metadata::new(inum, self.inodes.get(inum).unwrap()))
+    }
+
+    /// Returns information about the file at the path provided like `stat`, but about the symbolic
+    /// link itself if the path names one.
+    pub fn lstat<P: AsRef<Path>>(&mut self, path: P) -> Result<Metadata, SFSError> {
+        let inum = self.resolve_nofollow(self.cwd, path.as_ref())?;
+        Ok(Metadata::new(inum, self.inodes.get(inum).unwrap()))
+    }
+
+    /// Returns information about the file an open descriptor refers to.
+    pub fn fstat(&self, fd: FileDescriptor) -> Result<Metadata, SFSError> {
+        let file = self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        Ok(Metadata::new(
+            file.inum,
+            self.inodes.get(file.inum).unwrap(),
+        ))
+    }
+
+    /// Sets the access and modification times of the file at the path provided. The change time is
+    /// set to the current time unless both updates are `TimeUpdate::Omit`.
+    ///
+    /// Only the owner of the file or root may set either time to a given value. Setting them to the
+    /// current time is also allowed to anyone with write permission on the file.
+    pub fn utimens<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        accessed: TimeUpdate,
+        modified: TimeUpdate,
+    ) -> Result<(), SFSError> {
+        let inum = self.resolve(self.cwd, path.as_ref())?;
+        self.set_times(inum, accessed, modified)
+    }
+
+    /// Sets the access and modification times of the file an open descriptor refers to, see
+    /// `utimens`.
+    pub fn futimens(
+        &mut self,
+        fd: FileDescriptor,
+        accessed: TimeUpdate,
+        modified: TimeUpdate,
+    ) -> Result<(), SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        self.set_times(file.inum, accessed, modified)
+    }
+
+    /// Changes the permission bits of the file at the path provided to the lower 12 bits of `mode`,
+    /// following symbolic links. Only the owner of the file or root may change them. The setgid
+    /// bit is cleared instead of set for callers other than root outside the file's group.
+    pub fn chmod<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<(), SFSError> {
+        let inum = self.resolve(self.cwd, path.as_ref())?;
+        self.set_mode(inum, mode)
+    }
+
+    /// Changes the permission bits of the file an open descriptor refers to, see `chmod`.
+    pub fn fchmod(&mut self, fd: FileDescriptor, mode: u32) -> Result<(), SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        self.set_mode(file.inum, mode)
+    }
+
+    /// Changes the owner and group of the file at the path provided, following symbolic links. An
+    /// id of `None` is left unchanged. Root may give a file to anyone, its owner may only change its
+    /// group to one they are a member of. Changing the owner or group of anything but a directory
+    /// clears its setuid and setgid bits.
+    pub fn chown<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(), SFSError> {
+        let inum = self.resolve(self.cwd, path.as_ref())?;
+        self.set_owner(inum, uid, gid)
+    }
+
+    /// Changes the owner and group of the file an open descriptor refers to, see `chown`.
+    pub fn fchown(
+        &mut self,
+        fd: FileDescriptor,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(), SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        self.set_owner(file.inum, uid, gid)
+    }
+
+    /// Lists the entries of the directory at the path provided, see `ReadDir`.
+    pub fn read_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<ReadDir<'_, T>, SFSError> {
+        let dir = self.resolve(self.cwd, path.as_ref())?;
+        if !self.inodes.get(dir).unwrap().is_dir() {
+            return Err(SFSError::NotADirectory);
         }
+        self.check_access(dir, READ)?;
+
+        self.mark_accessed(dir);
+        Ok(ReadDir::new(self, dir))
     }
 
-    #[test]
-    fn create_non_existent_file_returns_handle() {
-        let dev = create_test_device();
+    /// Changes the working directory relative paths are resolved from, initially the root
+    /// directory. A working directory that is removed stays allocated until it is changed, but
+    /// nothing can be found or created in it.
+    pub fn chdir<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SFSError> {
+        let dir = self.resolve(self.cwd, path.as_ref())?;
+        self.set_cwd(dir)
+    }
 
-        let mut fs = SFS::create(dev).unwrap();
+    /// Sets the credentials every following call is checked against, like switching the user a
+    /// process runs as. Ids are stored in 16 bits, larger user and group ids are rejected.
+    pub fn set_credentials(&mut self, creds: Credentials) -> Result<(), SFSError> {
+        disk_id(creds.uid)?;
+        disk_id(creds.gid)?;
+        self.creds = creds;
+        Ok(())
+    }
 
-        assert_eq!(fs.open("/foo", OpenMode::CREATE).unwrap(), 1);
+    /// The credentials calls are currently checked against.
+    pub fn credentials(&self) -> &Credentials {
+        &self.creds
     }
 
-    #[test]
-    fn create_non_existent_file_with_missing_subdirectory_returns_error() {
-        let dev = create_test_device();
+    /// Changes the working directory to the directory an open descriptor refers to, see `chdir`.
+    pub fn fchdir(&mut self, fd: FileDescriptor) -> Result<(), SFSError> {
+        let dir = self.dir_fd(fd)?;
+        self.set_cwd(dir)
+    }
 
-        let mut fs = SFS::create(dev).unwrap();
+    /// Releases the descriptor, after which its number may be handed out again by `open`.
+    pub fn close(&mut self, fd: FileDescriptor) -> Result<(), SFSError> {
+        let file = self.handles.remove(fd).ok_or(SFSError::BadDescriptor)?;
 
-        assert!(fs.open("/foo/bar", OpenMode::CREATE).is_err());
+        // The last descriptor to an unlinked file is all that was keeping it alive.
+        if self.inodes.get(file.inum).unwrap().links_count == 0 && !self.is_held(file.inum) {
+            self.free_inode(file.inum)?;
+        }
+        Ok(())
     }
 
-    #[test]
-    fn can_create_and_reopen_initialized_filesystem() {
-        let disk = tempfile::NamedTempFile::new().unwrap();
-        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
-            .with_block_size(64)
-            .build()
-            .unwrap();
-        // Initialize the filesystem.
-        SFS::create(dev).unwrap();
+    /// Reads from the descriptor's current position into `buf`, advancing the position by the
+    /// number of bytes read. Returns zero once the end of the file is reached.
+    pub fn read(&mut self, fd: FileDescriptor, buf: &mut [u8]) -> Result<usize, SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        if !file.mode.is_readable() {
+            return Err(SFSError::BadDescriptor);
+        }
+        if self.inodes.get(file.inum).unwrap().is_dir() {
+            return Err(SFSError::IsADirectory);
+        }
 
-        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
-            .with_block_size(64)
-            // Don't reset initialized disk.
-            .clear_medium(false)
-            .build()
-            .unwrap();
-        let fs: SFS<FileBlockEmulator> = SFS::from_block_storage(dev).unwrap();
-        assert_eq!(fs.inodes.total_nodes(), 1);
+        let read = self.read_inode(file.inum, file.cursor as usize, buf)?;
+        self.handles.get_mut(fd).unwrap().cursor += read as u64;
+        self.mark_accessed(file.inum);
+        Ok(read)
+    }
+
+    /// Writes `buf` at the descriptor's current position, advancing the position by the number of
+    /// bytes written. Descriptors opened with `OpenMode::APPEND` always write at the end of the file.
+    pub fn write(&mut self, fd: FileDescriptor, buf: &[u8]) -> Result<usize, SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        if !file.mode.is_writable() {
+            return Err(SFSError::BadDescriptor);
+        }
+
+        let offset = if file.mode.contains(OpenMode::APPEND) {
+            self.inodes.get(file.inum).unwrap().size() as usize
+        } else {
+            file.cursor as usize
+        };
+        let written = self.write_inode(file.inum, offset, buf)?;
+        self.handles.get_mut(fd).unwrap().cursor = (offset + written) as u64;
+        Ok(written)
+    }
+
+    /// Moves the descriptor's position, returning the new offset from the start of the file.
+    /// Seeking past the end of the file is allowed, a following write leaves a gap of zeros.
+    pub fn seek(&mut self, fd: FileDescriptor, pos: SeekFrom) -> Result<u64, SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        let size = self.inodes.get(file.inum).unwrap().size() as i64;
+
+        let cursor = match pos {
+            SeekFrom::Start(offset) => i64::try_from(offset).ok(),
+            SeekFrom::End(offset) => size.checked_add(offset),
+            SeekFrom::Current(offset) => (file.cursor as i64).checked_add(offset),
+        }
+        .ok_or_else(|| SFSError::InvalidArgument("seek offset overflows".to_string()))?;
+        if cursor < 0 {
+            return Err(SFSError::InvalidArgument(
+                "seek to a negative offset".to_string(),
+            ));
+        }
+
+        self.handles.get_mut(fd).unwrap().cursor = cursor as u64;
+        Ok(cursor as u64)
+    }
+
+    /// Moves the descriptor to the start of the first data at or after `offset`, like `lseek` with
+    /// `SEEK_DATA`, returning the new position. Data is found a block at a time, every byte of a
+    /// block backed by the disk counts as data. Returns `SFSError::NoSuchOffset` if the file holds
+    /// no data past the offset.
+    pub fn seek_data(&mut self, fd: FileDescriptor, offset: u64) -> Result<u64, SFSError> {
+        self.seek_mapped(fd, offset, true)
+    }
+
+    /// Moves the descriptor to the start of the first hole at or after `offset`, like `lseek` with
+    /// `SEEK_HOLE`, returning the new position. The end of the file counts as a hole, so only an
+    /// offset at or past the end returns `SFSError::NoSuchOffset`.
+    pub fn seek_hole(&mut self, fd: FileDescriptor, offset: u64) -> Result<u64, SFSError> {
+        self.seek_mapped(fd, offset, false)
+    }
+
+    /// Sets the length of the file at the path provided, which the caller needs write permission
+    /// on, see `ftruncate`.
+    pub fn truncate<P: AsRef<Path>>(&mut self, path: P, len: u64) -> Result<(), SFSError> {
+        let inum = self.resolve(self.cwd, path.as_ref())?;
+        self.check_access(inum, WRITE)?;
+        self.set_len(inum, len)
+    }
+
+    /// Sets the length of the file an open descriptor refers to, which must be open for writing.
+    /// Shrinking a file frees the data blocks wholly beyond its new end, growing it leaves a hole
+    /// that reads as zeros without allocating any blocks.
+    pub fn ftruncate(&mut self, fd: FileDescriptor, len: u64) -> Result<(), SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        if !file.mode.is_writable() {
+            return Err(SFSError::BadDescriptor);
+        }
+        self.set_len(file.inum, len)
+    }
+
+    /// Writes the contents of `buf` into the file at `inum` starting at byte `offset`, allocating
+    /// data blocks as needed. Returns the number of bytes written, which is less than the length of
+    /// `buf` if the device fills up part way through, like a short `write(2)`. Running out of space
+    /// is only an error if nothing could be written.
+    ///
+    /// Writes that only cover part of a block preserve the rest of the block's existing contents.
+    /// Writing past the end of the file extends it, any gap left between the previous end of the
+    /// file and `offset` reads back as zeros.
+    fn write_inode(&mut self, inum: u32, offset: usize, buf: &[u8]) -> Result<usize, SFSError> {
+        let size = match self.inodes.get(inum) {
+            Some(node) => node.size() as usize,
+            None => return Err(SFSError::DoesNotExist),
+        };
+        if offset + buf.len() > MAX_FILE_SIZE {
+            return Err(SFSError::FileTooLarge);
+        }
+
+        let mut block_buf = [0; BLOCK_SIZE];
+        // The last run of blocks allocated by this write as (first index, first disk block, length).
+        let mut fresh_run = (0, 0, 0);
+        let mut written = 0;
+        let mut no_space = None;
+        while written < buf.len() {
+            let pos = offset + written;
+            let index = pos / BLOCK_SIZE;
+            let block_offset = pos % BLOCK_SIZE;
+            let len = std::cmp::min(BLOCK_SIZE - block_offset, buf.len() - written);
+
+            let (first, start, count) = fresh_run;
+            let (blocknr, fresh) = if index >= first && index < first + count {
+                (start + index - first, true)
+            } else {
+                let wanted = (offset + buf.len() - 1) / BLOCK_SIZE - index + 1;
+                let (blocknr, allocated) = match self.get_or_alloc_block(inum, index, wanted) {
+                    Ok(run) => run,
+                    Err(err) => {
+                        no_space = Some(err);
+                        break;
+                    }
+                };
+                fresh_run = (index, blocknr, allocated);
+                (blocknr, allocated > 0)
+            };
+            if fresh {
+                // Newly allocated blocks may hold stale data from a previous owner.
+                block_buf.iter_mut().for_each(|b| *b = 0);
+            } else if len < BLOCK_SIZE {
+                self.dev.read_block(blocknr, &mut block_buf)?;
+            }
+            block_buf[block_offset..block_offset + len]
+                .copy_from_slice(&buf[written..written + len]);
+            self.dev.write_block(blocknr, &mut block_buf)?;
+            written += len;
+        }
+
+        if written > 0 {
+            if offset + written > size {
+                self.inodes
+                    .get_mut(inum)
+                    .unwrap()
+                    .set_size((offset + written) as u64);
+            }
+            self.mark_modified(inum);
+        } else if let Some(err) = no_space {
+            return Err(err);
+        }
+        Ok(written)
+    }
+
+    /// Reads from the file at `inum` starting at byte `offset` into `buf`. Returns the number of
+    /// bytes read, which is less than the length of `buf` if the end of the file is reached.
+    pub(crate) fn read_inode(
+        &mut self,
+        inum: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, SFSError> {
+        let node = match self.inodes.get(inum) {
+            Some(node) => *node,
+            None => return Err(SFSError::DoesNotExist),
+        };
+        let size = node.size() as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let total = std::cmp::min(buf.len(), size - offset);
+        let extents = if node.uses_extents() {
+            Some(self.load_extents(&node)?)
+        } else {
+            None
+        };
+        let mut block_buf = [0; BLOCK_SIZE];
+        let mut read = 0;
+        while read < total {
+            let pos = offset + read;
+            let block_offset = pos % BLOCK_SIZE;
+            let len = std::cmp::min(BLOCK_SIZE - block_offset, total - read);
+
+            let blocknr = match &extents {
+                Some(extents) => extent::lookup(extents, (pos / BLOCK_SIZE) as u32).unwrap_or(HOLE),
+                None => self.get_block(&node, pos / BLOCK_SIZE)?,
+            };
+            match blocknr {
+                // Blocks that were never written to read as zeros.
+                HOLE => block_buf.iter_mut().for_each(|b| *b = 0),
+                blocknr => self.dev.read_block(blocknr as usize, &mut block_buf)?,
+            }
+            buf[read..read + len].copy_from_slice(&block_buf[block_offset..block_offset + len]);
+            read += len;
+        }
+        Ok(read)
+    }
+
+    /// Looks up the disk block backing the `index`th block of the file at `inum` however it is
+    /// mapped, returning `HOLE` if the file has none there.
+    fn lookup_block(&mut self, inum: u32, index: usize) -> Result<u32, SFSError> {
+        let node = *self.inodes.get(inum).unwrap();
+        if node.uses_extents() {
+            let extents = self.load_extents(&node)?;
+            return Ok(extent::lookup(&extents, index as u32).unwrap_or(HOLE));
+        }
+        self.get_block(&node, index)
+    }
+
+    /// Looks up the disk block backing the `index`th block of a file, returning `HOLE` if the file
+    /// has none there.
+    fn get_block(&mut self, node: &Inode, index: usize) -> Result<u32, SFSError> {
+        match BlockPath::new(index as u64).ok_or(SFSError::FileTooLarge)? {
+            BlockPath::Direct(i) => Ok(node.blocks[i]),
+            BlockPath::Indirect { depth, offsets } => {
+                let mut blocknr = node.indirect[depth - 1];
+                for &offset in &offsets[..depth] {
+                    if blocknr == HOLE {
+                        break;
+                    }
+                    blocknr = self.read_pointers(blocknr)?[offset];
+                }
+                Ok(blocknr)
+            }
+        }
+    }
+
+    /// Returns whether the `index`th block of a file mapped by block pointers is backed by the
+    /// disk along with the number of blocks from it on known to share that state. A hole left by a
+    /// missing indirect block spans every block the indirect block would have mapped.
+    fn pointer_run(&mut self, node: &Inode, index: usize) -> Result<(bool, usize), SFSError> {
+        let (depth, offsets) = match BlockPath::new(index as u64).ok_or(SFSError::FileTooLarge)? {
+            BlockPath::Direct(i) => return Ok((node.blocks[i] != HOLE, 1)),
+            BlockPath::Indirect { depth, offsets } => (depth, offsets),
+        };
+
+        // The blocks mapped through the pointer followed at each level and the position of
+        // `index` among them.
+        let mut span = POINTERS_PER_BLOCK.pow(depth as u32);
+        let mut pos = offsets[..depth]
+            .iter()
+            .fold(0, |pos, &offset| pos * POINTERS_PER_BLOCK + offset);
+        let mut blocknr = node.indirect[depth - 1];
+        for &offset in &offsets[..depth] {
+            if blocknr == HOLE {
+                return Ok((false, span - pos));
+            }
+            span /= POINTERS_PER_BLOCK;
+            pos %= span;
+            blocknr = self.read_pointers(blocknr)?[offset];
+        }
+        Ok((blocknr != HOLE, 1))
+    }
+
+    /// Finds the first block of a file from `from` up to `end` that is backed by the disk if
+    /// `mapped` is true, or that is a hole otherwise.
+    fn find_block(
+        &mut self,
+        inum: u32,
+        from: usize,
+        end: usize,
+        mapped: bool,
+    ) -> Result<Option<usize>, SFSError> {
+        let node = *self.inodes.get(inum).unwrap();
+        let extents = if node.uses_extents() {
+            Some(self.load_extents(&node)?)
+        } else {
+            None
+        };
+
+        let mut index = from;
+        while index < end {
+            let (is_mapped, len) = match &extents {
+                Some(extents) => {
+                    let (is_mapped, len) = extent::run(extents, index as u32);
+                    (is_mapped, len as usize)
+                }
+                None => self.pointer_run(&node, index)?,
+            };
+            if is_mapped == mapped {
+                return Ok(Some(index));
+            }
+            index += len;
+        }
+        Ok(None)
+    }
+
+    /// Looks up the disk block backing the `index`th block of a file, allocating a new data block
+    /// (and any indirect blocks leading to it) if the file has none there yet. Files mapped with
+    /// extents may be given a contiguous run of up to `wanted` blocks at once.
+    ///
+    /// Returns the disk block along with the number of consecutive blocks, starting with it, that
+    /// were newly allocated.
+    fn get_or_alloc_block(
+        &mut self,
+        inum: u32,
+        index: usize,
+        wanted: usize,
+    ) -> Result<(usize, usize), SFSError> {
+        let node = *self.inodes.get(inum).unwrap();
+        if node.uses_extents() {
+            return self.get_or_alloc_extent(inum, index as u32, wanted);
+        }
+
+        let (depth, offsets) = match BlockPath::new(index as u64).ok_or(SFSError::FileTooLarge)? {
+            BlockPath::Direct(i) => {
+                if node.blocks[i] != HOLE {
+                    return Ok((node.blocks[i] as usize, 0));
+                }
+                let blocknr = self.alloc_block(inum)?;
+                self.inodes.get_mut(inum).unwrap().blocks[i] = blocknr;
+                return Ok((blocknr as usize, 1));
+            }
+            BlockPath::Indirect { depth, offsets } => (depth, offsets),
+        };
+
+        let mut parent = node.indirect[depth - 1];
+        if parent == HOLE {
+            parent = self.alloc_block(inum)?;
+            self.write_pointers(parent, &mut [0; POINTERS_PER_BLOCK])?;
+            self.inodes.get_mut(inum).unwrap().indirect[depth - 1] = parent;
+        }
+        for (level, &offset) in offsets[..depth].iter().enumerate() {
+            let mut pointers = self.read_pointers(parent)?;
+            if pointers[offset] != HOLE {
+                parent = pointers[offset];
+                continue;
+            }
+
+            let blocknr = self.alloc_block(inum)?;
+            let is_data_block = level + 1 == depth;
+            if !is_data_block {
+                self.write_pointers(blocknr, &mut [0; POINTERS_PER_BLOCK])?;
+            }
+            pointers[offset] = blocknr;
+            self.write_pointers(parent, &mut pointers)?;
+            if is_data_block {
+                return Ok((blocknr as usize, 1));
+            }
+            parent = blocknr;
+        }
+        Ok((parent as usize, 0))
+    }
+
+    /// Maps the `index`th block of a file using extents, allocating a run of up to `wanted` blocks
+    /// if it is unmapped. The run is placed to continue the preceding extent on disk where possible
+    /// so it can be merged into it.
+    fn get_or_alloc_extent(
+        &mut self,
+        inum: u32,
+        index: u32,
+        wanted: usize,
+    ) -> Result<(usize, usize), SFSError> {
+        let node = *self.inodes.get(inum).unwrap();
+        let mut extents = self.load_extents(&node)?;
+        if let Some(blocknr) = extent::lookup(&extents, index) {
+            return Ok((blocknr as usize, 0));
+        }
+
+        let mut len = wanted;
+        if let Some(next) = extent::next_mapped(&extents, index) {
+            len = std::cmp::min(len, (next - index) as usize);
+        }
+        let available = self
+            .super_block
+            .free_blocks_count
+            .saturating_sub(self.super_block.reserved_blocks_count);
+        len = std::cmp::min(len, available as usize);
+        if len == 0 {
+            return Err(SFSError::NoSpace);
+        }
+
+        let goal = extent::goal(&extents, index).unwrap_or(self.first_free_hint());
+        let cap = self.super_block.total_blocks_count as usize;
+        let run = ContiguousAllocation::new(&self.data_map, Some(cap))
+            .find(goal as usize, len)
+            .ok_or(SFSError::NoSpace)?;
+        for blocknr in run.clone() {
+            self.data_map.set_reserved(blocknr);
+        }
+        if run.start as u32 == self.first_free_hint() {
+            self.super_block.free_list = run.end as u32;
+        }
+        self.super_block.free_blocks_count -= run.len() as u32;
+        self.dirty_super_block = true;
+        self.inodes.get_mut(inum).unwrap().block_count += run.len() as u32;
+
+        let new_extent = Extent {
+            logical: index,
+            start: run.start as u32,
+            len: run.len() as u32,
+        };
+        extent::insert(&mut extents, new_extent);
+        if let Err(e) = self.store_extents(inum, &extents) {
+            for blocknr in run.clone() {
+                self.free_block(blocknr as u32);
+            }
+            self.inodes.get_mut(inum).unwrap().block_count -= run.len() as u32;
+            return Err(e);
+        }
+        Ok((run.start, run.len()))
+    }
+
+    /// Reads every extent of a file, following the extent index if they outgrew the inode.
+    fn load_extents(&mut self, node: &Inode) -> Result<Vec<Extent>, SFSError> {
+        if node.flags & EXTENT_INDEX_FLAG == 0 {
+            return Ok(extent::unpack(&node.blocks));
+        }
+
+        let mut extents = vec![];
+        let mut block_buf = [0; BLOCK_SIZE];
+        for entry in extent::unpack(&node.blocks) {
+            self.dev.read_block(entry.start as usize, &mut block_buf)?;
+            extents.extend(extent::parse_block(&block_buf, entry.len as usize));
+        }
+        Ok(extents)
+    }
+
+    /// Records the extents of a file. Extents are kept in the inode while they fit, otherwise they
+    /// are written to index blocks, reusing the blocks already held by the file.
+    fn store_extents(&mut self, inum: u32, extents: &[Extent]) -> Result<(), SFSError> {
+        let node = *self.inodes.get(inum).unwrap();
+        let mut index_blocks: Vec<u32> = if node.flags & EXTENT_INDEX_FLAG != 0 {
+            extent::unpack(&node.blocks)
+                .iter()
+                .map(|entry| entry.start)
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let needed = if extents.len() <= INLINE_EXTENTS {
+            0
+        } else {
+            (extents.len() - 1) / EXTENTS_PER_BLOCK + 1
+        };
+        if needed > INLINE_EXTENTS {
+            return Err(SFSError::FileTooLarge);
+        }
+        while index_blocks.len() < needed {
+            let blocknr = self.alloc_block(inum)?;
+            index_blocks.push(blocknr);
+        }
+        for blocknr in index_blocks.split_off(needed) {
+            self.free_block(blocknr);
+            self.inodes.get_mut(inum).unwrap().block_count -= 1;
+        }
+
+        if needed == 0 {
+            let node = self.inodes.get_mut(inum).unwrap();
+            node.blocks = extent::pack(extents);
+            node.flags &= !EXTENT_INDEX_FLAG;
+            return Ok(());
+        }
+
+        let mut index = vec![];
+        for (chunk, &blocknr) in extents.chunks(EXTENTS_PER_BLOCK).zip(&index_blocks) {
+            self.dev
+                .write_block(blocknr as usize, &mut extent::serialize_block(chunk))?;
+            index.push(Extent {
+                logical: chunk[0].logical,
+                start: blocknr,
+                len: chunk.len() as u32,
+            });
+        }
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.blocks = extent::pack(&index);
+        node.flags |= EXTENT_INDEX_FLAG;
+        Ok(())
+    }
+
+    /// The block searches for a free block start from, see `SuperBlock::free_list`. Images written before
+    /// the hint was kept hold zero, which is never past the first free block.
+    fn first_free_hint(&self) -> u32 {
+        std::cmp::max(
+            self.super_block.free_list,
+            self.super_block.data_region_start,
+        )
+    }
+
+    /// Takes a free block from the data region, charging it to the inode.
+    fn alloc_block(&mut self, inum: u32) -> Result<u32, SFSError> {
+        if self.super_block.free_blocks_count <= self.super_block.reserved_blocks_count {
+            return Err(SFSError::NoSpace);
+        }
+        let cap = self.super_block.total_blocks_count as usize;
+        let blocknr = NextAvailableAllocation::new(&self.data_map, Some(cap))
+            .starting_at(self.first_free_hint() as usize)
+            .next()
+            .ok_or(SFSError::NoSpace)?;
+        self.data_map.set_reserved(blocknr);
+        self.super_block.free_list = blocknr as u32 + 1;
+        self.super_block.free_blocks_count -= 1;
+        self.dirty_super_block = true;
+        self.inodes.get_mut(inum).unwrap().block_count += 1;
+        Ok(blocknr as u32)
+    }
+
+    /// Returns a data block to the free pool.
+    fn free_block(&mut self, blocknr: u32) {
+        self.data_map.set_free(blocknr as usize);
+        self.super_block.free_list = std::cmp::min(self.first_free_hint(), blocknr);
+        self.super_block.free_blocks_count += 1;
+        self.dirty_super_block = true;
+    }
+
+    /// Frees the blocks of a file from the `keep`th block on, along with the indirect or extent
+    /// index blocks no longer needed to map the blocks before it.
+    fn free_blocks_from(&mut self, inum: u32, keep: usize) -> Result<(), SFSError> {
+        let node = *self.inodes.get(inum).unwrap();
+        if node.uses_extents() {
+            let keep = keep as u32;
+            let mut extents = self.load_extents(&node)?;
+            for extent in extents.iter_mut().filter(|extent| extent.end() > keep) {
+                let from = std::cmp::max(extent.logical, keep);
+                for index in from..extent.end() {
+                    self.release_block(inum, extent.start + (index - extent.logical));
+                }
+                extent.len = from - extent.logical;
+            }
+            extents.retain(|extent| extent.len != 0);
+            return self.store_extents(inum, &extents);
+        }
+
+        for i in keep..DIRECT_BLOCKS {
+            if node.blocks[i] != HOLE {
+                self.release_block(inum, node.blocks[i]);
+                self.inodes.get_mut(inum).unwrap().blocks[i] = HOLE;
+            }
+        }
+        let mut first = DIRECT_BLOCKS;
+        for (level, &blocknr) in node.indirect.iter().enumerate() {
+            let depth = level + 1;
+            if blocknr != HOLE && self.free_indirect(inum, blocknr, depth, first, keep)? {
+                self.inodes.get_mut(inum).unwrap().indirect[level] = HOLE;
+            }
+            first += POINTERS_PER_BLOCK.pow(depth as u32);
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks reached through an indirect block `depth` levels above the data blocks
+    /// that hold the `keep`th block of the file or any after it, `first` being the first block of
+    /// the file the indirect block maps. Returns true if the indirect block was freed too, which
+    /// happens once it no longer points at anything.
+    fn free_indirect(
+        &mut self,
+        inum: u32,
+        blocknr: u32,
+        depth: usize,
+        first: usize,
+        keep: usize,
+    ) -> Result<bool, SFSError> {
+        let span = POINTERS_PER_BLOCK.pow(depth as u32 - 1);
+        let mut pointers = self.read_pointers(blocknr)?;
+        let mut changed = false;
+        for (i, pointer) in pointers.iter_mut().enumerate() {
+            let child_first = first + i * span;
+            if *pointer == HOLE || child_first + span <= keep {
+                continue;
+            }
+            let freed = if depth > 1 {
+                self.free_indirect(inum, *pointer, depth - 1, child_first, keep)?
+            } else {
+                self.release_block(inum, *pointer);
+                true
+            };
+            if freed {
+                *pointer = HOLE;
+                changed = true;
+            }
+        }
+
+        if pointers.iter().all(|&pointer| pointer == HOLE) {
+            self.release_block(inum, blocknr);
+            return Ok(true);
+        }
+        if changed {
+            self.write_pointers(blocknr, &mut pointers)?;
+        }
+        Ok(false)
+    }
+
+    /// Returns a block held by the inode to the free pool.
+    fn release_block(&mut self, inum: u32, blocknr: u32) {
+        self.free_block(blocknr);
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.block_count = node.block_count.saturating_sub(1);
+    }
+
+    fn read_pointers(&mut self, blocknr: u32) -> Result<[u32; POINTERS_PER_BLOCK], SFSError> {
+        let mut pointers = [0; POINTERS_PER_BLOCK];
+        self.dev
+            .read_block(blocknr as usize, pointers.as_bytes_mut())?;
+        Ok(pointers)
+    }
+
+    fn write_pointers(
+        &mut self,
+        blocknr: u32,
+        pointers: &mut [u32; POINTERS_PER_BLOCK],
+    ) -> Result<(), SFSError> {
+        self.dev
+            .write_block(blocknr as usize, pointers.as_bytes_mut())?;
+        Ok(())
+    }
+
+    /// Walks every component of a path except the last, returning the directory the final
+    /// component lives in along with its name. Relative paths are walked from the directory `dir`.
+    /// Returns `None` for the root directory, which has no parent.
+    ///
+    /// "." and ".." are looked up like any other name, ".." of a directory reached through a
+    /// symbolic link leading to its actual parent. A path ending in a slash must name a directory.
+    /// It must also name something unless `missing_ok` is set, since only a directory may be
+    /// created under such a name: callers creating one, or reporting missing names themselves,
+    /// set it.
+    fn resolve_parent(
+        &mut self,
+        dir: u32,
+        path: &Path,
+        missing_ok: bool,
+    ) -> Result<Option<(u32, OsString)>, SFSError> {
+        let bytes = path.as_os_str().as_bytes();
+        if bytes.is_empty() {
+            return Err(SFSError::DoesNotExist);
+        }
+        if bytes.len() >= MAX_PATH_LEN {
+            return Err(SFSError::NameTooLong);
+        }
+        let start = if path.has_root() { 0 } else { dir };
+        // Only a removed directory has no links, nothing can be found or created in it.
+        if self.inodes.get(start).unwrap().links_count == 0 {
+            return Err(SFSError::DoesNotExist);
+        }
+
+        let mut parts = components(bytes);
+        let name = match parts.pop() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        for part in parts.iter().chain(Some(&name)) {
+            dir::validate_name(part)?;
+        }
+
+        let parent = self.walk(start, &parts, &mut 0)?;
+        if !self.inodes.get(parent).unwrap().is_dir() {
+            return Err(SFSError::NotADirectory);
+        }
+        self.check_access(parent, EXECUTE)?;
+        if has_trailing_slash(path) {
+            match self.lookup(parent, name)? {
+                Some(inum) => {
+                    let inum = self.follow(parent, inum, &mut 0)?;
+                    if !self.inodes.get(inum).unwrap().is_dir() {
+                        return Err(SFSError::NotADirectory);
+                    }
+                }
+                None if !missing_ok => return Err(SFSError::NotADirectory),
+                None => (),
+            }
+        }
+
+        Ok(Some((parent, name.to_os_string())))
+    }
+
+    /// Walks the components of a path from the directory `dir`, following every symbolic link met
+    /// along the way, and returns the inode reached. `links` counts the links followed so far.
+    /// Every directory walked through must grant search permission.
+    fn walk(&mut self, dir: u32, parts: &[&OsStr], links: &mut usize) -> Result<u32, SFSError> {
+        let mut inum = dir;
+        for part in parts {
+            let dir = inum;
+            if !self.inodes.get(dir).unwrap().is_dir() {
+                return Err(SFSError::NotADirectory);
+            }
+            self.check_access(dir, EXECUTE)?;
+            inum = self.lookup(dir, part)?.ok_or(SFSError::DoesNotExist)?;
+            inum = self.follow(dir, inum, links)?;
+        }
+        Ok(inum)
+    }
+
+    /// Returns the inode `inum` refers to, following it while it is a symbolic link. Relative
+    /// targets are resolved from `dir`, the directory the link was found in.
+    fn follow(&mut self, dir: u32, inum: u32, links: &mut usize) -> Result<u32, SFSError> {
+        if !self.is_symlink(inum) {
+            return Ok(inum);
+        }
+        *links += 1;
+        if *links > MAX_SYMLINKS {
+            return Err(SFSError::SymlinkLoop);
+        }
+
+        let target = self.read_link(inum)?;
+        let start = if target.starts_with(b"/") { 0 } else { dir };
+        self.walk(start, &components(&target), links)
+    }
+
+    /// Reads the target of a symbolic link.
+    fn read_link(&mut self, inum: u32) -> Result<Vec<u8>, SFSError> {
+        let node = self.inodes.get(inum).unwrap();
+        if node.flags & INLINE_DATA_FLAG != 0 {
+            return Ok(node.inline_data().to_vec());
+        }
+        self.read_file(inum)
+    }
+
+    fn is_symlink(&self, inum: u32) -> bool {
+        self.inodes.get(inum).unwrap().file_type().is_symlink()
+    }
+
+    /// Returns the type of file held by the inode, if it is allocated.
+    pub(crate) fn file_type(&self, inum: u32) -> Option<FileType> {
+        self.inodes.get(inum).map(|node| node.file_type())
+    }
+
+    /// Walks a path from the directory `dir` returning the inode it refers to, following a
+    /// symbolic link named by the final component.
+    fn resolve(&mut self, dir: u32, path: &Path) -> Result<u32, SFSError> {
+        match self.resolve_parent(dir, path, true)? {
+            Some((parent, name)) => {
+                let inum = self.lookup(parent, &name)?.ok_or(SFSError::DoesNotExist)?;
+                self.follow(parent, inum, &mut 0)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Walks a path like `resolve`, returning a symbolic link named by the final component itself
+    /// unless the path ends in a slash.
+    fn resolve_nofollow(&mut self, dir: u32, path: &Path) -> Result<u32, SFSError> {
+        if has_trailing_slash(path) {
+            return self.resolve(dir, path);
+        }
+        match self.resolve_parent(dir, path, true)? {
+            Some((parent, name)) => self.lookup(parent, &name)?.ok_or(SFSError::DoesNotExist),
+            None => Ok(0),
+        }
+    }
+
+    /// Returns the directory an open descriptor refers to.
+    fn dir_fd(&self, fd: FileDescriptor) -> Result<u32, SFSError> {
+        let file = self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        if !self.inodes.get(file.inum).unwrap().is_dir() {
+            return Err(SFSError::NotADirectory);
+        }
+        Ok(file.inum)
+    }
+
+    /// Makes `dir` the working directory, releasing the previous one if it was removed.
+    fn set_cwd(&mut self, dir: u32) -> Result<(), SFSError> {
+        if !self.inodes.get(dir).unwrap().is_dir() {
+            return Err(SFSError::NotADirectory);
+        }
+        self.check_access(dir, EXECUTE)?;
+
+        let previous = std::mem::replace(&mut self.cwd, dir);
+        if self.inodes.get(previous).unwrap().links_count == 0 && !self.is_held(previous) {
+            self.free_inode(previous)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the caller is granted the `access` permissions on the inode, see
+    /// `Credentials::can_access`.
+    fn check_access(&self, inum: u32, access: u16) -> Result<(), SFSError> {
+        if !self
+            .creds
+            .can_access(self.inodes.get(inum).unwrap(), access)
+        {
+            return Err(SFSError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Checks the caller may remove the entry for `inum` from the directory `dir`, which takes
+    /// write permission on the directory and, if it is sticky, owning one of the two.
+    fn check_remove(&self, dir: u32, inum: u32) -> Result<(), SFSError> {
+        self.check_access(dir, WRITE)?;
+        let dir = self.inodes.get(dir).unwrap();
+        if !self.creds.can_remove(dir, self.inodes.get(inum).unwrap()) {
+            return Err(SFSError::NotPermitted);
+        }
+        Ok(())
+    }
+
+    /// Makes the caller the owner of a new inode linked from `parent`. Inodes created in a setgid
+    /// directory take the directory's group instead, new directories inheriting the setgid bit so
+    /// the same holds beneath them.
+    fn init_owner(&mut self, inum: u32, parent: u32) {
+        let parent = *self.inodes.get(parent).unwrap();
+        // Both ids were checked to fit when the credentials were set.
+        let (uid, gid) = (self.creds.uid as u16, self.creds.gid as u16);
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.uid = uid;
+        node.gid = gid;
+        if parent.mode & SETGID != 0 {
+            node.gid = parent.gid;
+            if node.is_dir() {
+                node.mode |= SETGID;
+            }
+        }
+    }
+
+    fn set_mode(&mut self, inum: u32, mode: u32) -> Result<(), SFSError> {
+        let node = self.inodes.get(inum).unwrap();
+        if !self.creds.owns(node) {
+            return Err(SFSError::NotPermitted);
+        }
+
+        let mut bits = (mode & 0o7777) as u16;
+        if !self.creds.is_root() && !self.creds.in_group(u32::from(node.gid)) {
+            bits &= !SETGID;
+        }
+        self.inodes.get_mut(inum).unwrap().set_permissions(bits);
+        self.mark_changed(inum);
+        Ok(())
+    }
+
+    fn set_owner(&mut self, inum: u32, uid: Option<u32>, gid: Option<u32>) -> Result<(), SFSError> {
+        let node = *self.inodes.get(inum).unwrap();
+        let new_uid = uid.map(disk_id).transpose()?.unwrap_or(node.uid);
+        let new_gid = gid.map(disk_id).transpose()?.unwrap_or(node.gid);
+        if !self.creds.is_root() {
+            let gives_away = new_uid != node.uid;
+            let foreign_group = new_gid != node.gid && !self.creds.in_group(u32::from(new_gid));
+            if !self.creds.owns(&node) || gives_away || foreign_group {
+                return Err(SFSError::NotPermitted);
+            }
+        }
+
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.uid = new_uid;
+        node.gid = new_gid;
+        if !node.is_dir() && (uid.is_some() || gid.is_some()) {
+            node.mode &= !(SETUID | SETGID);
+        }
+        self.mark_changed(inum);
+        Ok(())
+    }
+
+    /// Whether an open descriptor or the working directory keeps the inode alive.
+    fn is_held(&self, inum: u32) -> bool {
+        self.cwd == inum || self.handles.is_open(inum)
+    }
+
+    /// Finds the inode linked under `name` in the directory `dir`.
+    fn lookup(&mut self, dir: u32, name: &OsStr) -> Result<Option<u32>, SFSError> {
+        if !self.inodes.get(dir).unwrap().is_dir() {
+            return Err(SFSError::NotADirectory);
+        }
+
+        for index in self.entry_blocks(dir, name)? {
+            let entries = dir::parse_block(&self.read_dir_block(dir, index)?)?;
+            if let Some(entry) = entries.into_iter().find(|entry| entry.name == name) {
+                return Ok(Some(entry.inum));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Links `name` to the inode in the directory `dir`, replacing any entry already linked under
+    /// it. Only the block the entry lands in is written. A directory is indexed once its entries
+    /// no longer fit in the blocks it has.
+    fn insert_entry(&mut self, dir: u32, name: OsString, inum: u32) -> Result<(), SFSError> {
+        let entry = DirEntry {
+            file_type: self.file_type(inum).ok_or(SFSError::CorruptDirectory)?,
+            name,
+            inum,
+        };
+        if self.is_indexed(dir) {
+            if entry.name != "." && entry.name != ".." {
+                return self.insert_indexed_entry(dir, entry);
+            }
+            // The root's "." and ".." records cover its index, they can only be repointed.
+            let mut root = self.read_dir_block(dir, 0)?;
+            if !dir::set_record(&mut root, &entry)? {
+                return Err(SFSError::CorruptDirectory);
+            }
+            return self.write_dir_block(dir, 0, &root);
+        }
+
+        // The first block with room, should no block already link the name.
+        let mut room = None;
+        for index in 0..self.dir_blocks(dir) {
+            let mut block = self.read_dir_block(dir, index)?;
+            if dir::set_record(&mut block, &entry)? {
+                return self.write_dir_block(dir, index, &block);
+            }
+            if room.is_none() && dir::insert_record(&mut block, &entry)? {
+                room = Some((index, block));
+            }
+        }
+        match room {
+            Some((index, block)) => self.write_dir_block(dir, index, &block),
+            None => {
+                self.build_index(dir)?;
+                self.insert_indexed_entry(dir, entry)
+            }
+        }
+    }
+
+    /// Links an entry into the leaf of an indexed directory covering its name, splitting the leaf
+    /// if it is full.
+    fn insert_indexed_entry(&mut self, dir: u32, entry: DirEntry) -> Result<(), SFSError> {
+        let path = self.find_leaf(dir, htree::hash(entry.name.as_bytes()))?;
+        let mut block = self.read_dir_block(dir, path.leaf)?;
+        if dir::set_record(&mut block, &entry)? || dir::insert_record(&mut block, &entry)? {
+            return self.write_dir_block(dir, path.leaf, &block);
+        }
+
+        let mut entries = dir::parse_block(&block)?;
+        entries.push(entry);
+        let (lower, split_hash, upper) = htree::split_leaf(entries).ok_or(SFSError::NoSpace)?;
+        let upper_block = self.dir_blocks(dir);
+        self.write_dir_block(dir, path.leaf, &dir::serialize_entries(&lower))?;
+        self.write_dir_block(dir, upper_block, &dir::serialize_entries(&upper))?;
+        self.insert_index_entry(
+            dir,
+            path,
+            IndexEntry {
+                hash: split_hash,
+                block: upper_block,
+            },
+        )
+    }
+
+    /// Adds an entry for a newly split block next to the entry that led to the block it was split
+    /// from. Interior nodes are added as the index grows, failing once the root and every node is
+    /// full.
+    fn insert_index_entry(
+        &mut self,
+        dir: u32,
+        path: IndexPath,
+        entry: IndexEntry,
+    ) -> Result<(), SFSError> {
+        let IndexPath {
+            mut root,
+            root_pos,
+            node,
+            ..
+        } = path;
+        match node {
+            None if root.entries.len() < htree::ROOT_LIMIT => {
+                root.entries.insert(root_pos + 1, entry);
+            }
+            None => {
+                // Move the full root down into a node of its own, the root pointing at it alone.
+                let node_block = self.dir_blocks(dir);
+                let mut entries = std::mem::replace(
+                    &mut root.entries,
+                    vec![IndexEntry {
+                        hash: 0,
+                        block: node_block,
+                    }],
+                );
+                entries.insert(root_pos + 1, entry);
+                root.levels += 1;
+                self.write_dir_block(dir, node_block, &htree::serialize_node(&entries))?;
+            }
+            Some((node_block, mut entries, pos)) => {
+                entries.insert(pos + 1, entry);
+                if entries.len() <= htree::NODE_LIMIT {
+                    return self.write_dir_block(dir, node_block, &htree::serialize_node(&entries));
+                }
+                if root.entries.len() >= htree::ROOT_LIMIT {
+                    return Err(SFSError::NoSpace);
+                }
+
+                let upper = entries.split_off(entries.len() / 2);
+                let upper_block = self.dir_blocks(dir);
+                self.write_dir_block(dir, node_block, &htree::serialize_node(&entries))?;
+                self.write_dir_block(dir, upper_block, &htree::serialize_node(&upper))?;
+                root.entries.insert(
+                    root_pos + 1,
+                    IndexEntry {
+                        hash: upper[0].hash,
+                        block: upper_block,
+                    },
+                );
+            }
+        }
+        self.write_dir_block(dir, 0, &root.serialize())
+    }
+
+    /// Removes the entry linked under `name` from the directory `dir` in place, returning the
+    /// inode it linked to.
+    fn remove_entry(&mut self, dir: u32, name: &OsStr) -> Result<Option<u32>, SFSError> {
+        for index in self.entry_blocks(dir, name)? {
+            let mut block = self.read_dir_block(dir, index)?;
+            if let Some(inum) = dir::remove_record(&mut block, name)? {
+                self.write_dir_block(dir, index, &block)?;
+                return Ok(Some(inum));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Converts a directory that outgrew its blocks to a hashed index with a single leaf, then
+    /// adds its entries back through the index.
+    fn build_index(&mut self, dir: u32) -> Result<(), SFSError> {
+        let entries = self.read_entries(dir)?;
+        let parent = entries
+            .iter()
+            .find(|entry| entry.name == "..")
+            .ok_or(SFSError::CorruptDirectory)?
+            .inum;
+
+        self.release_blocks(dir)?;
+        let root = IndexRoot {
+            dir,
+            parent,
+            levels: 0,
+            entries: vec![IndexEntry { hash: 0, block: 1 }],
+        };
+        self.write_dir_block(dir, 0, &root.serialize())?;
+        self.write_dir_block(dir, 1, &dir::serialize_entries(&[]))?;
+        self.inodes.get_mut(dir).unwrap().flags |= DIR_INDEX_FLAG;
+
+        for entry in entries {
+            if entry.name != "." && entry.name != ".." {
+                self.insert_indexed_entry(dir, entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The blocks of the directory that may hold an entry named `name`. Only one block of an
+    /// indexed directory does, the root for "." and "..", the leaf covering the name otherwise.
+    fn entry_blocks(&mut self, dir: u32, name: &OsStr) -> Result<Range<u32>, SFSError> {
+        if !self.is_indexed(dir) {
+            return Ok(0..self.dir_blocks(dir));
+        }
+        if name == "." || name == ".." {
+            return Ok(0..1);
+        }
+        let leaf = self.find_leaf(dir, htree::hash(name.as_bytes()))?.leaf;
+        Ok(leaf..leaf + 1)
+    }
+
+    /// Walks the index of the directory down to the leaf covering `hash`.
+    fn find_leaf(&mut self, dir: u32, hash: u32) -> Result<IndexPath, SFSError> {
+        let root = IndexRoot::parse(&self.read_dir_block(dir, 0)?)?;
+        let root_pos = htree::find(&root.entries, hash);
+        let mut leaf = root.entries[root_pos].block;
+
+        let node = if root.levels > 0 {
+            let entries = htree::parse_node(&self.read_dir_block(dir, leaf)?)?;
+            let pos = htree::find(&entries, hash);
+            let node_block = leaf;
+            leaf = entries[pos].block;
+            Some((node_block, entries, pos))
+        } else {
+            None
+        };
+        Ok(IndexPath {
+            root,
+            root_pos,
+            node,
+            leaf,
+        })
+    }
+
+    fn is_indexed(&self, dir: u32) -> bool {
+        self.inodes.get(dir).unwrap().flags & DIR_INDEX_FLAG != 0
+    }
+
+    /// The number of blocks making up the directory, also the index of the next block added to it.
+    fn dir_blocks(&self, dir: u32) -> u32 {
+        (self.inodes.get(dir).unwrap().size() / BLOCK_SIZE as u64) as u32
+    }
+
+    fn read_dir_block(&mut self, dir: u32, index: u32) -> Result<Vec<u8>, SFSError> {
+        let mut block_buf = vec![0; BLOCK_SIZE];
+        let read = self.read_inode(dir, index as usize * BLOCK_SIZE, &mut block_buf)?;
+        if read != BLOCK_SIZE {
+            return Err(SFSError::CorruptDirectory);
+        }
+        Ok(block_buf)
+    }
+
+    fn write_dir_block(&mut self, dir: u32, index: u32, block: &[u8]) -> Result<(), SFSError> {
+        self.write_inode(dir, index as usize * BLOCK_SIZE, block)?;
+        Ok(())
+    }
+
+    /// Whether the directory holds anything besides its "." and ".." entries.
+    fn is_empty_dir(&mut self, dir: u32) -> Result<bool, SFSError> {
+        Ok(self
+            .read_entries(dir)?
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == ".."))
+    }
+
+    /// Whether `dir` is `inum` or one of its parent directories.
+    fn is_ancestor(&mut self, dir: u32, mut inum: u32) -> Result<bool, SFSError> {
+        loop {
+            if inum == dir {
+                return Ok(true);
+            }
+            if inum == 0 {
+                return Ok(false);
+            }
+            inum = self
+                .lookup(inum, OsStr::new(".."))?
+                .ok_or(SFSError::DoesNotExist)?;
+        }
+    }
+
+    /// The current time according to the file system's clock.
+    fn now(&self) -> Timestamp {
+        Timestamp::from_system_time(self.clock.now()).unwrap_or_default()
+    }
+
+    /// Stamps every time of a newly created inode with the current time.
+    fn init_times(&mut self, inum: u32) {
+        let now = self.now();
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.set_created(now);
+        node.set_modified(now);
+        node.set_changed(now);
+        node.set_accessed(now);
+    }
+
+    /// Records a change to the contents of the inode, which also changes the inode itself.
+    fn mark_modified(&mut self, inum: u32) {
+        let now = self.now();
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.set_modified(now);
+        node.set_changed(now);
+    }
+
+    /// Records a change to the inode's attributes, such as its links, that left its contents alone.
+    fn mark_changed(&mut self, inum: u32) {
+        let now = self.now();
+        self.inodes.get_mut(inum).unwrap().set_changed(now);
+    }
+
+    /// Records a read of the inode's contents as allowed by the access time policy.
+    fn mark_accessed(&mut self, inum: u32) {
+        let now = self.now();
+        let node = self.inodes.get(inum).unwrap();
+        let update = match self.atime {
+            AtimePolicy::Strict => true,
+            AtimePolicy::NoAtime => false,
+            AtimePolicy::Relatime => {
+                let accessed = node.accessed();
+                accessed <= node.modified()
+                    || accessed <= node.changed()
+                    || now.secs.saturating_sub(accessed.secs) >= RELATIME_INTERVAL_SECS
+            }
+        };
+        if update {
+            self.inodes.get_mut(inum).unwrap().set_accessed(now);
+        }
+    }
+
+    fn set_times(
+        &mut self,
+        inum: u32,
+        accessed: TimeUpdate,
+        modified: TimeUpdate,
+    ) -> Result<(), SFSError> {
+        let node = self.inodes.get(inum).unwrap();
+        let explicit = |update| match update {
+            TimeUpdate::To(_) => true,
+            TimeUpdate::Now | TimeUpdate::Omit => false,
+        };
+        if explicit(accessed) || explicit(modified) {
+            if !self.creds.owns(node) {
+                return Err(SFSError::NotPermitted);
+            }
+        } else if (accessed, modified) != (TimeUpdate::Omit, TimeUpdate::Omit)
+            && !self.creds.owns(node)
+        {
+            self.check_access(inum, WRITE)?;
+        }
+
+        let now = self.now();
+        let resolve = |update| match update {
+            TimeUpdate::Now => Ok(Some(now)),
+            TimeUpdate::Omit => Ok(None),
+            TimeUpdate::To(time) => Timestamp::from_system_time(time).map(Some).ok_or_else(|| {
+                SFSError::InvalidArgument("times before the unix epoch".to_string())
+            }),
+        };
+        let accessed = resolve(accessed)?;
+        let modified = resolve(modified)?;
+
+        let node = self.inodes.get_mut(inum).unwrap();
+        if let Some(accessed) = accessed {
+            node.set_accessed(accessed);
+        }
+        if let Some(modified) = modified {
+            node.set_modified(modified);
+        }
+        if accessed.is_some() || modified.is_some() {
+            node.set_changed(now);
+        }
+        Ok(())
+    }
+
+    /// Removes a single link to the inode, releasing it once nothing links to it and no descriptor
+    /// holds it open. Directories are only ever linked from their parent so removing that entry
+    /// drops every link.
+    fn drop_link(&mut self, inum: u32) -> Result<(), SFSError> {
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.links_count = if node.is_dir() {
+            0
+        } else {
+            node.links_count - 1
+        };
+
+        if node.links_count == 0 && !self.is_held(inum) {
+            self.free_inode(inum)?;
+        } else {
+            self.mark_changed(inum);
+        }
+        Ok(())
+    }
+
+    /// Returns every data block held by the inode to the free pool, leaving an empty file.
+    fn release_blocks(&mut self, inum: u32) -> Result<(), SFSError> {
+        self.free_blocks_from(inum, 0)?;
+
+        let node = self.inodes.get_mut(inum).unwrap();
+        node.flags &= !(EXTENT_INDEX_FLAG | DIR_INDEX_FLAG | INLINE_DATA_FLAG);
+        node.block_count = 0;
+        node.set_size(0);
+        Ok(())
+    }
+
+    /// Moves the descriptor to the first data, or hole, at or after `offset`, see `seek_data`.
+    fn seek_mapped(
+        &mut self,
+        fd: FileDescriptor,
+        offset: u64,
+        data: bool,
+    ) -> Result<u64, SFSError> {
+        let file = *self.handles.get(fd).ok_or(SFSError::BadDescriptor)?;
+        let size = self.inodes.get(file.inum).unwrap().size();
+        if offset >= size {
+            return Err(SFSError::NoSuchOffset);
+        }
+
+        let from = (offset / BLOCK_SIZE as u64) as usize;
+        let end = ((size - 1) / BLOCK_SIZE as u64) as usize + 1;
+        let cursor = match self.find_block(file.inum, from, end, data)? {
+            Some(index) => std::cmp::max(offset, (index * BLOCK_SIZE) as u64),
+            None if data => return Err(SFSError::NoSuchOffset),
+            None => size,
+        };
+        self.handles.get_mut(fd).unwrap().cursor = cursor;
+        Ok(cursor)
+    }
+
+    /// Shrinks or grows a regular file to `len` bytes.
+    fn set_len(&mut self, inum: u32, len: u64) -> Result<(), SFSError> {
+        let node = self.inodes.get(inum).unwrap();
+        if node.is_dir() {
+            return Err(SFSError::IsADirectory);
+        }
+        if len > MAX_FILE_SIZE as u64 {
+            return Err(SFSError::FileTooLarge);
+        }
+
+        let size = node.size();
+        if len < size {
+            let len = len as usize;
+            self.free_blocks_from(inum, (len + BLOCK_SIZE - 1) / BLOCK_SIZE)?;
+
+            // The rest of the new last block must read as zeros should the file grow again.
+            let tail = len % BLOCK_SIZE;
+            let blocknr = self.lookup_block(inum, len / BLOCK_SIZE)?;
+            if tail != 0 && blocknr != HOLE {
+                let mut block_buf = [0; BLOCK_SIZE];
+                self.dev.read_block(blocknr as usize, &mut block_buf)?;
+                block_buf[tail..].iter_mut().for_each(|b| *b = 0);
+                self.dev.write_block(blocknr as usize, &mut block_buf)?;
+            }
+        }
+        if len != size {
+            self.inodes.get_mut(inum).unwrap().set_size(len);
+            self.mark_modified(inum);
+        }
+        Ok(())
+    }
+
+    /// Allocates an inode owned by the caller with one of the `InodeGroup::new_*` functions.
+    fn new_inode(
+        &mut self,
+        allocate: fn(&mut InodeGroup) -> Option<u32>,
+        parent: u32,
+    ) -> Result<u32, SFSError> {
+        if self.super_block.free_inodes_count == 0 {
+            return Err(SFSError::NoSpace);
+        }
+        let inum = allocate(&mut self.inodes).ok_or(SFSError::NoSpace)?;
+        self.init_owner(inum, parent);
+        self.init_times(inum);
+        self.super_block.free_inodes_count -= 1;
+        self.dirty_super_block = true;
+        Ok(inum)
+    }
+
+    /// Releases the inode and all of its data blocks.
+    fn free_inode(&mut self, inum: u32) -> Result<(), SFSError> {
+        self.release_blocks(inum)?;
+        self.inodes.remove(inum);
+        self.super_block.free_inodes_count += 1;
+        self.dirty_super_block = true;
+        Ok(())
+    }
+
+    /// Writes out a whole directory holding the entries, used for new directories and converting
+    /// old ones. Any other change to a directory updates it in place, see `insert_entry`.
+    fn write_dir(&mut self, dir: u32, entries: Vec<(OsString, u32)>) -> Result<(), SFSError> {
+        let mut records = entries
+            .into_iter()
+            .map(|(name, inum)| {
+                let file_type = self.file_type(inum).ok_or(SFSError::CorruptDirectory)?;
+                Ok(DirEntry {
+                    name,
+                    inum,
+                    file_type,
+                })
+            })
+            .collect::<Result<Vec<DirEntry>, SFSError>>()?;
+        // Keep "." and ".." at the start of the directory.
+        records.sort_by_key(|entry| (entry.name != ".", entry.name != ".."));
+        let contents = dir::serialize_entries(&records);
+
+        info!("Writing {} entries to dir inode {}.", records.len(), dir);
+        if self.write_inode(dir, 0, &contents)? < contents.len() {
+            return Err(SFSError::NoSpace);
+        }
+        // The directory may have shrunk, drop anything left over from the previous contents.
+        self.inodes
+            .get_mut(dir)
+            .unwrap()
+            .set_size(contents.len() as u64);
+        Ok(())
+    }
+
+    /// Reads every entry of the directory.
+    fn read_entries(&mut self, inum: u32) -> Result<Vec<DirEntry>, SFSError> {
+        let content = self.read_file(inum)?;
+        if content.len() % BLOCK_SIZE != 0 {
+            return Err(SFSError::CorruptDirectory);
+        }
+
+        let mut entries = vec![];
+        for block in content.chunks(BLOCK_SIZE) {
+            entries.extend(dir::parse_block(block)?);
+        }
+        Ok(entries)
+    }
+
+    /// Rewrites every directory of an image formatted without `FEATURE_DIR_RECORDS` as binary
+    /// entry records. Converting the format leaves the times of the directories alone.
+    ///
+    /// The very first images kept neither "." and ".." entries nor link counts, the missing entries
+    /// are added and the links of nodes left at zero counted from the entries naming them.
+    fn upgrade_legacy_dirs(&mut self) -> Result<(), SFSError> {
+        let mut dirs = vec![];
+        for dir in self.inodes.directories() {
+            let content = self.read_file(dir)?;
+            dirs.push((dir, dir::parse_legacy_entries(&content)?));
+        }
+        let is_dot = |name: &OsString| name == "." || name == "..";
+        let parents: HashMap<u32, u32> = dirs
+            .iter()
+            .flat_map(|(dir, entries)| {
+                entries
+                    .iter()
+                    .filter(|(name, _)| !is_dot(name))
+                    .map(move |&(_, inum)| (inum, *dir))
+            })
+            .collect();
+
+        let mut links: HashMap<u32, u16> = HashMap::new();
+        for (dir, mut entries) in dirs {
+            if !entries.iter().any(|(name, _)| name == ".") {
+                entries.push((OsString::from("."), dir));
+            }
+            if !entries.iter().any(|(name, _)| name == "..") {
+                let parent = parents.get(&dir).copied().unwrap_or(dir);
+                entries.push((OsString::from(".."), parent));
+            }
+            for &(_, inum) in entries.iter() {
+                *links.entry(inum).or_default() += 1;
+            }
+
+            let node = self.inodes.get(dir).unwrap();
+            let (modified, changed) = (node.modified(), node.changed());
+            self.write_dir(dir, entries)?;
+            let node = self.inodes.get_mut(dir).unwrap();
+            node.set_modified(modified);
+            node.set_changed(changed);
+        }
+
+        for (inum, count) in links {
+            if let Some(node) = self.inodes.get_mut(inum) {
+                if node.links_count == 0 {
+                    node.links_count = count;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_file(&mut self, inum: u32) -> Result<Vec<u8>, SFSError> {
+        let size = match self.inodes.get(inum) {
+            Some(node) => node.size() as usize,
+            None => return Err(SFSError::DoesNotExist),
+        };
+
+        let mut content = vec![0; size];
+        self.read_inode(inum, 0, &mut content)?;
+        Ok(content)
+    }
+}
+
+/// Splits a path into the names along it. Repeated slashes are skipped while "." and ".." are kept,
+/// to be looked up like any other name.
+fn components(path: &[u8]) -> Vec<&OsStr> {
+    path.split(|&byte| byte == b'/')
+        .filter(|part| !part.is_empty())
+        .map(OsStr::from_bytes)
+        .collect()
+}
+
+/// Converts a user or group id to the 16 bits it is stored in.
+fn disk_id(id: u32) -> Result<u16, SFSError> {
+    u16::try_from(id).map_err(|_| {
+        SFSError::InvalidArgument(format!("ids above {} are not supported", std::u16::MAX))
+    })
+}
+
+/// Whether the path ends in a slash after naming something, requiring that to be a directory.
+fn has_trailing_slash(path: &Path) -> bool {
+    let bytes = path.as_os_str().as_bytes();
+    bytes.len() > 1 && bytes.ends_with(b"/")
+}
+
+impl<T: BlockStorage> Drop for SFS<T> {
+    fn drop(&mut self) {
+        // Files unlinked while open were only being kept alive by their descriptors, a removed
+        // working directory by being the working directory.
+        let orphans: Vec<u32> = self
+            .handles
+            .inums()
+            .chain(Some(self.cwd))
+            .filter(|&inum| self.inodes.get(inum).unwrap().links_count == 0)
+            .collect();
+        for inum in orphans {
+            if self.inodes.get(inum).is_some() {
+                if let Err(e) = self.free_inode(inum) {
+                    error!("Failed to free unlinked inode {} on drop: {}", inum, e);
+                }
+            }
+        }
+
+        if let Err(e) = self.sync() {
+            error!("Failed to flush file system metadata on drop: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::State;
+    use crate::io::{FileBlockEmulator, FileBlockEmulatorBuilder};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn create_test_device() -> FileBlockEmulator {
+        let dev = tempfile::tempfile().unwrap();
+        FileBlockEmulatorBuilder::from(dev)
+            .with_block_size(64)
+            .build()
+            .expect("Could not initialize disk emulator.")
+    }
+
+    #[test]
+    fn root_dir_returns_root_fd() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        assert_eq!(fs.open("/", OpenMode::RO).unwrap(), 0);
+    }
+
+    #[test]
+    fn file_not_found_without_create_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        let result = fs.open("/foo", OpenMode::RO);
+        match result.unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => assert!(false, "Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn create_non_existent_file_returns_handle() {
+        let dev = create_test_device();
+
+        let mut fs = SFS::create(dev).unwrap();
+
+        let fd = fs.open("/foo", OpenMode::CREATE).unwrap();
+        assert_eq!(fs.handles.get(fd).unwrap().inum, 1);
+    }
+
+    #[test]
+    fn create_non_existent_file_with_missing_subdirectory_returns_error() {
+        let dev = create_test_device();
+
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert!(fs.open("/foo/bar", OpenMode::CREATE).is_err());
+    }
+
+    #[test]
+    fn can_write_and_read_back_file_contents() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+
+        assert_eq!(fs.write(fd, b"hello world").unwrap(), 11);
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+
+        let mut buf = [0; 11];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 11);
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[test]
+    fn write_at_offset_spanning_blocks_preserves_surrounding_data() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, &vec![0x11; 2 * BLOCK_SIZE]).unwrap();
+
+        // Overwrite a range straddling the first block boundary.
+        fs.seek(fd, SeekFrom::Start(BLOCK_SIZE as u64 - 2)).unwrap();
+        fs.write(fd, &[0x22; 4]).unwrap();
+
+        let mut buf = vec![0; 2 * BLOCK_SIZE];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 2 * BLOCK_SIZE);
+        assert_eq!(
+            &buf[BLOCK_SIZE - 3..BLOCK_SIZE + 3],
+            &[0x11, 0x22, 0x22, 0x22, 0x22, 0x11]
+        );
+        let inum = fs.handles.get(fd).unwrap().inum;
+        assert_eq!(fs.inodes.get(inum).unwrap().size(), 2 * BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn read_stops_at_end_of_file() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"abc").unwrap();
+
+        let mut buf = [0; 8];
+        fs.seek(fd, SeekFrom::Start(1)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"bc");
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn write_past_end_of_file_reads_gap_as_zeros() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.seek(fd, SeekFrom::Start(BLOCK_SIZE as u64 + 10))
+            .unwrap();
+        fs.write(fd, b"x").unwrap();
+
+        let mut buf = vec![0xFF; BLOCK_SIZE + 11];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), BLOCK_SIZE + 11);
+        assert!(buf[..BLOCK_SIZE + 10].iter().all(|&b| b == 0));
+        assert_eq!(buf[BLOCK_SIZE + 10], b'x');
+    }
+
+    #[test]
+    fn write_beyond_max_file_size_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.seek(fd, SeekFrom::Start(MAX_FILE_SIZE as u64)).unwrap();
+
+        match fs.write(fd, b"x").unwrap_err() {
+            SFSError::FileTooLarge => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn files_grow_past_direct_blocks_into_indirect_blocks() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        let data: Vec<u8> = (0..20 * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect();
+
+        assert_eq!(fs.write(fd, &data).unwrap(), data.len());
+
+        let mut buf = vec![0; data.len()];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), data.len());
+        assert!(buf == data);
+        assert_ne!(fs.inodes.get(1).unwrap().indirect[0], 0);
+        // Twenty data blocks plus the indirect block pointing at five of them.
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 21);
+    }
+
+    #[test]
+    fn sparse_writes_reach_double_indirect_blocks() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        let offset = ((DIRECT_BLOCKS + POINTERS_PER_BLOCK + 3) * BLOCK_SIZE) as u64;
+
+        fs.seek(fd, SeekFrom::Start(offset)).unwrap();
+        fs.write(fd, b"deep").unwrap();
+
+        let mut buf = [0xFF; 8];
+        fs.seek(fd, SeekFrom::Start(offset - 4)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"\0\0\0\0deep");
+        let node = fs.inodes.get(1).unwrap();
+        assert_eq!(node.indirect[0], 0);
+        assert_ne!(node.indirect[1], 0);
+        // The data block and the two levels of indirect blocks leading to it.
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks - 3);
+    }
+
+    fn assert_seeks_around_holes(fs: &mut SFS<FileBlockEmulator>) {
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"start").unwrap();
+        fs.seek(fd, SeekFrom::Start(3 * BLOCK_SIZE as u64)).unwrap();
+        fs.write(fd, b"end").unwrap();
+        let size = 3 * BLOCK_SIZE as u64 + 3;
+
+        assert_eq!(fs.seek_data(fd, 0).unwrap(), 0);
+        assert_eq!(fs.seek_hole(fd, 2).unwrap(), BLOCK_SIZE as u64);
+        assert_eq!(
+            fs.seek_hole(fd, BLOCK_SIZE as u64 + 5).unwrap(),
+            BLOCK_SIZE as u64 + 5
+        );
+        assert_eq!(
+            fs.seek_data(fd, BLOCK_SIZE as u64).unwrap(),
+            3 * BLOCK_SIZE as u64
+        );
+        let mut buf = [0; 3];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"end");
+        assert_eq!(fs.seek_hole(fd, 3 * BLOCK_SIZE as u64).unwrap(), size);
+        for offset in [size, size + 10].iter() {
+            match fs.seek_data(fd, *offset).unwrap_err() {
+                SFSError::NoSuchOffset => (),
+                _ => panic!("Unexpected error type."),
+            }
+            match fs.seek_hole(fd, *offset).unwrap_err() {
+                SFSError::NoSuchOffset => (),
+                _ => panic!("Unexpected error type."),
+            }
+        }
+    }
+
+    #[test]
+    fn seek_data_and_hole_find_unmapped_blocks() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        assert_seeks_around_holes(&mut fs);
+    }
+
+    #[test]
+    fn seek_data_and_hole_find_gaps_between_extents() {
+        let mut fs = create_extent_fs();
+        assert_seeks_around_holes(&mut fs);
+    }
+
+    #[test]
+    fn sparse_files_only_hold_their_data_blocks() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs
+            .open("/disk.img", OpenMode::CREATE | OpenMode::RW)
+            .unwrap();
+        let size = 1 << 30;
+        let data = ((DIRECT_BLOCKS + 2 * POINTERS_PER_BLOCK + 7) * BLOCK_SIZE) as u64;
+        fs.ftruncate(fd, size).unwrap();
+        fs.seek(fd, SeekFrom::Start(data)).unwrap();
+        fs.write(fd, b"boot").unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+
+        let mut buf = [0xFF; 8];
+        fs.seek(fd, SeekFrom::Start(data - 4)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"\0\0\0\0boot");
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+        assert_eq!(fs.fstat(fd).unwrap().len(), size);
+        // The data block and the two levels of indirect blocks leading to it.
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 3);
+        assert_eq!(fs.seek_data(fd, 0).unwrap(), data);
+        assert_eq!(fs.seek_hole(fd, data).unwrap(), data + BLOCK_SIZE as u64);
+        match fs.seek_data(fd, data + BLOCK_SIZE as u64).unwrap_err() {
+            SFSError::NoSuchOffset => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn unlink_frees_indirect_blocks() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; 20 * BLOCK_SIZE]).unwrap();
+        fs.seek(
+            fd,
+            SeekFrom::Start(((DIRECT_BLOCKS + POINTERS_PER_BLOCK) * BLOCK_SIZE) as u64),
+        )
+        .unwrap();
+        fs.write(fd, b"x").unwrap();
+        fs.close(fd).unwrap();
+
+        fs.unlink("/foo").unwrap();
+
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+        let cap = fs.super_block.total_blocks_count as usize;
+        assert!(NextAvailableAllocation::new(&fs.data_map, Some(cap)).eq(9..cap));
+    }
+
+    #[test]
+    fn indirect_blocks_persist_across_remount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        let data: Vec<u8> = (0..18 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        fs.write(fd, &data).unwrap();
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        let mut buf = vec![0; data.len()];
+
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), data.len());
+        assert!(buf == data);
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 19);
+    }
+
+    fn create_extent_fs() -> SFS<FileBlockEmulator> {
+        SFS::format(
+            create_test_device(),
+            &FormatOptions::new().with_extents(true),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn sequential_writes_are_mapped_by_a_single_extent() {
+        let mut fs = create_extent_fs();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+
+        fs.write(fd, &data[..BLOCK_SIZE / 2]).unwrap();
+        fs.write(fd, &data[BLOCK_SIZE / 2..]).unwrap();
+
+        let node = fs.inodes.get(1).unwrap();
+        assert!(node.uses_extents());
+        let extents = extent::unpack(&node.blocks);
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].len, 20);
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 20);
+        let mut buf = vec![0; data.len()];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), data.len());
+        assert!(buf == data);
+    }
+
+    #[test]
+    fn fragmented_extents_overflow_into_index_block() {
+        let mut fs = create_extent_fs();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let a = fs.open("/a", OpenMode::CREATE | OpenMode::RW).unwrap();
+        let b = fs.open("/b", OpenMode::CREATE | OpenMode::WO).unwrap();
+        // Interleaving the files leaves each block of "/a" in an extent of its own.
+        for i in 0..10 {
+            fs.write(a, &[i; BLOCK_SIZE]).unwrap();
+            fs.write(b, &[0xFF; BLOCK_SIZE]).unwrap();
+        }
+
+        let node = *fs.inodes.get(1).unwrap();
+        assert_ne!(node.flags & EXTENT_INDEX_FLAG, 0);
+        assert_eq!(fs.load_extents(&node).unwrap().len(), 10);
+        // Ten data blocks and the index block.
+        assert_eq!(fs.fstat(a).unwrap().blocks(), 11);
+        let mut buf = vec![0; 10 * BLOCK_SIZE];
+        fs.seek(a, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(a, &mut buf).unwrap(), buf.len());
+        for (i, block) in buf.chunks(BLOCK_SIZE).enumerate() {
+            assert!(block.iter().all(|&byte| byte == i as u8));
+        }
+
+        fs.close(a).unwrap();
+        fs.close(b).unwrap();
+        fs.unlink("/a").unwrap();
+        fs.unlink("/b").unwrap();
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+    }
+
+    #[test]
+    fn extent_files_read_gaps_as_zeros() {
+        let mut fs = create_extent_fs();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"start").unwrap();
+        fs.seek(fd, SeekFrom::Start(3 * BLOCK_SIZE as u64)).unwrap();
+        fs.write(fd, b"end").unwrap();
+        fs.seek(fd, SeekFrom::Start(BLOCK_SIZE as u64)).unwrap();
+        fs.write(fd, b"middle").unwrap();
+
+        let mut buf = vec![0xFF; 3 * BLOCK_SIZE + 3];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), buf.len());
+        assert_eq!(&buf[..5], b"start");
+        assert_eq!(&buf[BLOCK_SIZE..BLOCK_SIZE + 6], b"middle");
+        assert!(buf[BLOCK_SIZE + 6..3 * BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert_eq!(&buf[3 * BLOCK_SIZE..], b"end");
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 3);
+    }
+
+    #[test]
+    fn extents_persist_across_remount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::format(dev, &FormatOptions::new().with_extents(true)).unwrap();
+        fs.mkdir("/dir").unwrap();
+        let fd = fs
+            .open("/dir/foo", OpenMode::CREATE | OpenMode::WO)
+            .unwrap();
+        fs.write(fd, &vec![0x42; 3 * BLOCK_SIZE]).unwrap();
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        let fd = fs.open("/dir/foo", OpenMode::RO).unwrap();
+        let mut buf = vec![0; 3 * BLOCK_SIZE];
+
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().all(|&b| b == 0x42));
+        let bar = fs.open("/bar", OpenMode::CREATE).unwrap();
+        assert!(fs
+            .inodes
+            .get(fs.handles.get(bar).unwrap().inum)
+            .unwrap()
+            .uses_extents());
+    }
+
+    #[test]
+    fn files_grow_beyond_four_gigabytes() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        let offset = 5 << 30;
+
+        fs.seek(fd, SeekFrom::Start(offset)).unwrap();
+        fs.write(fd, b"far").unwrap();
+
+        assert_eq!(fs.stat("/foo").unwrap().len(), offset + 3);
+        assert_eq!(fs.seek(fd, SeekFrom::End(-3)).unwrap(), offset);
+        let mut buf = [0; 4];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"far");
+        assert_ne!(fs.inodes.get(1).unwrap().indirect[2], 0);
+    }
+
+    #[test]
+    fn created_files_are_listed_in_parent_directory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let foo = fs.open("/foo", OpenMode::CREATE).unwrap();
+        let bar = fs.open("/bar", OpenMode::CREATE).unwrap();
+
+        let foo_again = fs.open("/foo", OpenMode::RO).unwrap();
+        let bar_again = fs.open("/bar", OpenMode::RO).unwrap();
+        assert_eq!(
+            fs.handles.get(foo).unwrap().inum,
+            fs.handles.get(foo_again).unwrap().inum
+        );
+        assert_eq!(
+            fs.handles.get(bar).unwrap().inum,
+            fs.handles.get(bar_again).unwrap().inum
+        );
+    }
+
+    #[test]
+    fn descriptors_track_independent_positions() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let writer = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(writer, b"abcdef").unwrap();
+
+        let reader = fs.open("/foo", OpenMode::RO).unwrap();
+        let mut buf = [0; 3];
+        fs.read(reader, &mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+        assert_eq!(fs.seek(writer, SeekFrom::Current(0)).unwrap(), 6);
+        assert_eq!(fs.seek(reader, SeekFrom::Current(0)).unwrap(), 3);
+    }
+
+    #[test]
+    fn seek_from_end_is_relative_to_file_size() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"abcdef").unwrap();
+
+        assert_eq!(fs.seek(fd, SeekFrom::End(-2)).unwrap(), 4);
+        assert!(fs.seek(fd, SeekFrom::End(-7)).is_err());
+    }
+
+    #[test]
+    fn seek_overflowing_offset_is_invalid() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"foobar").unwrap();
+
+        let overflowing = [
+            SeekFrom::End(std::i64::MAX),
+            SeekFrom::Current(std::i64::MAX),
+            SeekFrom::Start(std::u64::MAX),
+        ];
+        for &pos in overflowing.iter() {
+            match fs.seek(fd, pos).unwrap_err() {
+                SFSError::InvalidArgument(_) => (),
+                _ => panic!("Unexpected error type."),
+            }
+        }
+        assert_eq!(fs.seek(fd, SeekFrom::Current(0)).unwrap(), 6);
+    }
+
+    #[test]
+    fn write_to_read_only_descriptor_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.close(fd).unwrap();
+
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        match fs.write(fd, b"x").unwrap_err() {
+            SFSError::BadDescriptor => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn closed_descriptor_can_no_longer_be_used() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        fs.close(fd).unwrap();
+
+        let mut buf = [0; 1];
+        assert!(fs.read(fd, &mut buf).is_err());
+        assert!(fs.close(fd).is_err());
+    }
+
+    #[test]
+    fn open_modes_combine_like_open_flags() {
+        let mode = OpenMode::CREATE | OpenMode::RW;
+
+        assert!(mode.contains(OpenMode::CREATE));
+        assert!(!mode.contains(OpenMode::DIRECTORY));
+        assert!(mode.is_readable() && mode.is_writable());
+        assert!(OpenMode::RO.is_readable() && !OpenMode::RO.is_writable());
+        assert!(!OpenMode::WO.is_readable() && OpenMode::WO.is_writable());
+    }
+
+    #[test]
+    fn read_from_write_only_descriptor_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"abc").unwrap();
+
+        let fd = fs.open("/foo", OpenMode::WO).unwrap();
+        let mut buf = [0; 3];
+        match fs.read(fd, &mut buf).unwrap_err() {
+            SFSError::BadDescriptor => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn read_write_descriptor_can_read_and_write() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        let fd = fs.open("/foo", OpenMode::RW).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 3];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    fn open_directory_on_regular_file_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        assert!(fs.open("/", OpenMode::DIRECTORY).is_ok());
+        match fs.open("/foo", OpenMode::DIRECTORY).unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn open_directory_for_writing_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        match fs.open("/", OpenMode::RW).unwrap_err() {
+            SFSError::IsADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn path_through_regular_file_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        match fs.open("/foo/bar", OpenMode::RO).unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn create_existing_file_opens_it() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"abc").unwrap();
+
+        let again = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        assert_eq!(
+            fs.handles.get(fd).unwrap().inum,
+            fs.handles.get(again).unwrap().inum
+        );
+        let mut buf = [0; 3];
+        fs.read(again, &mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+        assert_eq!(fs.inodes.total_nodes(), 2);
+    }
+
+    #[test]
+    fn exclusive_create_of_existing_file_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE | OpenMode::EXCL).unwrap();
+
+        match fs
+            .open("/foo", OpenMode::CREATE | OpenMode::EXCL)
+            .unwrap_err()
+        {
+            SFSError::AlreadyExists => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn truncate_on_open_frees_data_blocks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; 2 * BLOCK_SIZE]).unwrap();
+        let inum = fs.handles.get(fd).unwrap().inum;
+        let blocks = fs.inodes.get(inum).unwrap().blocks;
+
+        let fd = fs.open("/foo", OpenMode::RW | OpenMode::TRUNC).unwrap();
+
+        assert_eq!(fs.inodes.get(inum).unwrap().size(), 0);
+        assert_eq!(fs.data_map.get(blocks[0] as usize), State::Free);
+        assert_eq!(fs.data_map.get(blocks[1] as usize), State::Free);
+        let mut buf = [0; 1];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn truncate_frees_blocks_beyond_new_end() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        let data: Vec<u8> = (0..20 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        fs.write(fd, &data).unwrap();
+        let deep = ((DIRECT_BLOCKS + POINTERS_PER_BLOCK + 3) * BLOCK_SIZE) as u64;
+        fs.seek(fd, SeekFrom::Start(deep)).unwrap();
+        fs.write(fd, b"deep").unwrap();
+
+        let len = 5 * BLOCK_SIZE + 10;
+        fs.truncate("/foo", len as u64).unwrap();
+
+        assert_eq!(fs.fstat(fd).unwrap().len(), len as u64);
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 6);
+        assert_eq!(fs.inodes.get(1).unwrap().indirect, [0; 3]);
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks - 6);
+        let mut buf = vec![0; data.len()];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), len);
+        assert!(buf[..len] == data[..len]);
+
+        fs.ftruncate(fd, 0).unwrap();
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+    }
+
+    #[test]
+    fn truncate_keeps_indirect_blocks_still_in_use() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, &vec![0x11; 20 * BLOCK_SIZE]).unwrap();
+
+        fs.ftruncate(fd, 17 * BLOCK_SIZE as u64).unwrap();
+
+        // Seventeen data blocks plus the indirect block pointing at two of them.
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 18);
+        assert_ne!(fs.inodes.get(1).unwrap().indirect[0], 0);
+        fs.seek(fd, SeekFrom::End(0)).unwrap();
+        fs.write(fd, b"x").unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 19);
+    }
+
+    #[test]
+    fn truncate_zeroes_the_rest_of_the_last_block() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, &[b'x'; BLOCK_SIZE]).unwrap();
+
+        fs.ftruncate(fd, 100).unwrap();
+        fs.ftruncate(fd, BLOCK_SIZE as u64).unwrap();
+
+        let mut buf = vec![0xFF; BLOCK_SIZE];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), BLOCK_SIZE);
+        assert!(buf[..100].iter().all(|&b| b == b'x'));
+        assert!(buf[100..].iter().all(|&b| b == 0));
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 1);
+    }
+
+    #[test]
+    fn extending_a_file_leaves_a_hole() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+
+        fs.ftruncate(fd, 10 * BLOCK_SIZE as u64).unwrap();
+
+        let meta = fs.fstat(fd).unwrap();
+        assert_eq!(meta.len(), 10 * BLOCK_SIZE as u64);
+        assert_eq!(meta.blocks(), 1);
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+        let mut buf = [0xFF; 8];
+        fs.seek(fd, SeekFrom::Start(5 * BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 8);
+        assert_eq!(buf, [0; 8]);
+    }
+
+    #[test]
+    fn truncate_splits_extents() {
+        let mut fs = create_extent_fs();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, &vec![0x22; 20 * BLOCK_SIZE]).unwrap();
+
+        fs.ftruncate(fd, 3 * BLOCK_SIZE as u64 + 1).unwrap();
+
+        let extents = extent::unpack(&fs.inodes.get(1).unwrap().blocks);
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].len, 4);
+        assert_eq!(fs.fstat(fd).unwrap().blocks(), 4);
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks - 4);
+    }
+
+    #[test]
+    fn invalid_truncate_returns_error() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        match fs.ftruncate(fd, 0).unwrap_err() {
+            SFSError::BadDescriptor => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.truncate("/", 0).unwrap_err() {
+            SFSError::IsADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.truncate("/foo", MAX_FILE_SIZE as u64 + 1).unwrap_err() {
+            SFSError::FileTooLarge => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn append_writes_land_at_end_of_file() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"abc").unwrap();
+
+        let appender = fs.open("/foo", OpenMode::WO | OpenMode::APPEND).unwrap();
+        fs.seek(appender, SeekFrom::Start(0)).unwrap();
+        fs.write(appender, b"def").unwrap();
+        fs.write(fd, b"ghi").unwrap();
+        fs.write(appender, b"jkl").unwrap();
+
+        let reader = fs.open("/foo", OpenMode::RO).unwrap();
+        let mut buf = [0; 9];
+        fs.read(reader, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcghijkl");
+    }
+
+    #[test]
+    fn can_create_files_in_new_directory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+
+        let fd = fs
+            .open("/a/b/foo", OpenMode::CREATE | OpenMode::RW)
+            .unwrap();
+        fs.write(fd, b"abc").unwrap();
+
+        assert!(fs.open("/a/b", OpenMode::DIRECTORY).is_ok());
+        let fd = fs.open("/a/b/foo", OpenMode::RO).unwrap();
+        let mut buf = [0; 3];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    fn new_directory_links_to_itself_and_parent() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+
+        let a = fs.lookup(0, OsStr::new("a")).unwrap().unwrap();
+        assert_eq!(fs.lookup(a, OsStr::new(".")).unwrap(), Some(a));
+        assert_eq!(fs.lookup(a, OsStr::new("..")).unwrap(), Some(0));
+        assert_eq!(fs.inodes.get(a).unwrap().links_count, 2);
+        assert_eq!(fs.inodes.get(0).unwrap().links_count, 3);
+    }
+
+    #[test]
+    fn mkdir_over_existing_entry_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        match fs.mkdir("/foo").unwrap_err() {
+            SFSError::AlreadyExists => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn rmdir_releases_directory_inode() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        let a = fs.lookup(0, OsStr::new("a")).unwrap().unwrap();
+
+        fs.rmdir("/a").unwrap();
+
+        assert_eq!(fs.lookup(0, OsStr::new("a")).unwrap(), None);
+        assert_eq!(fs.inodes.allocations().get(a as usize), State::Free);
+        assert_eq!(fs.inodes.get(0).unwrap().links_count, 2);
+        assert_eq!(fs.inodes.total_nodes(), 1);
+    }
+
+    #[test]
+    fn rmdir_of_non_empty_directory_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.open("/a/foo", OpenMode::CREATE).unwrap();
+
+        match fs.rmdir("/a").unwrap_err() {
+            SFSError::DirectoryNotEmpty => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn rmdir_of_regular_file_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        match fs.rmdir("/foo").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn new_filesystem_counts_root_as_allocated() {
+        let dev = create_test_device();
+        let fs = SFS::create(dev).unwrap();
+
+        assert_eq!(
+            fs.super_block.free_inodes_count,
+            fs.super_block.inodes_count - 1
+        );
+        assert_eq!(
+            fs.super_block.free_blocks_count,
+            fs.super_block.blocks_count - 1
+        );
+    }
+
+    #[test]
+    fn unlink_frees_inode_and_data_blocks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let free_inodes = fs.super_block.free_inodes_count;
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; 2 * BLOCK_SIZE]).unwrap();
+        let inum = fs.handles.get(fd).unwrap().inum;
+        let blocks = fs.inodes.get(inum).unwrap().blocks;
+        fs.close(fd).unwrap();
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks - 2);
+
+        fs.unlink("/foo").unwrap();
+
+        assert!(fs.open("/foo", OpenMode::RO).is_err());
+        assert!(fs.inodes.get(inum).is_none());
+        assert_eq!(fs.inodes.allocations().get(inum as usize), State::Free);
+        assert_eq!(fs.data_map.get(blocks[0] as usize), State::Free);
+        assert_eq!(fs.data_map.get(blocks[1] as usize), State::Free);
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+    }
+
+    #[test]
+    fn link_adds_name_for_same_inode() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"shared").unwrap();
+        fs.close(fd).unwrap();
+
+        fs.link("/foo", "/a/bar").unwrap();
+
+        let foo = fs.stat("/foo").unwrap();
+        let bar = fs.stat("/a/bar").unwrap();
+        assert_eq!(foo.ino(), bar.ino());
+        assert_eq!(bar.nlink(), 2);
+        let fd = fs.open("/a/bar", OpenMode::RO).unwrap();
+        let mut buf = [0; 6];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"shared");
+    }
+
+    #[test]
+    fn linked_file_is_freed_after_last_unlink() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; BLOCK_SIZE]).unwrap();
+        fs.close(fd).unwrap();
+        fs.link("/foo", "/bar").unwrap();
+        let inum = fs.stat("/foo").unwrap().ino();
+
+        fs.unlink("/foo").unwrap();
+
+        assert_eq!(fs.stat("/bar").unwrap().nlink(), 1);
+        assert_eq!(fs.stat("/bar").unwrap().len(), BLOCK_SIZE as u64);
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks - 1);
+
+        fs.unlink("/bar").unwrap();
+
+        assert!(fs.inodes.get(inum).is_none());
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+    }
+
+    #[test]
+    fn link_to_directory_is_not_permitted() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+
+        match fs.link("/a", "/b").unwrap_err() {
+            SFSError::NotPermitted => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert!(fs.stat("/b").is_err());
+        assert_eq!(fs.stat("/a").unwrap().nlink(), 2);
+    }
+
+    #[test]
+    fn link_over_existing_name_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.open("/bar", OpenMode::CREATE).unwrap();
+
+        match fs.link("/foo", "/bar").unwrap_err() {
+            SFSError::AlreadyExists => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.link("/missing", "/baz").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert_eq!(fs.stat("/foo").unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn rename_between_links_of_same_file_does_nothing() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.link("/foo", "/bar").unwrap();
+
+        fs.rename("/foo", "/bar").unwrap();
+
+        assert_eq!(
+            fs.stat("/foo").unwrap().ino(),
+            fs.stat("/bar").unwrap().ino()
+        );
+        assert_eq!(fs.stat("/bar").unwrap().nlink(), 2);
+    }
+
+    fn read_all(fs: &mut SFS<FileBlockEmulator>, path: &str) -> Vec<u8> {
+        let fd = fs.open(path, OpenMode::RO).unwrap();
+        let mut buf = vec![0; 64];
+        let read = fs.read(fd, &mut buf).unwrap();
+        fs.close(fd).unwrap();
+        buf.truncate(read);
+        buf
+    }
+
+    #[test]
+    fn short_symlink_targets_are_stored_inline() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+
+        fs.symlink("foo/bar", "/link").unwrap();
+
+        assert_eq!(fs.readlink("/link").unwrap(), PathBuf::from("foo/bar"));
+        let meta = fs.lstat("/link").unwrap();
+        assert!(meta.is_symlink());
+        assert_eq!(meta.len(), 7);
+        assert_eq!(meta.blocks(), 0);
+        assert_eq!(meta.permissions().mode(), 0o777);
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+    }
+
+    #[test]
+    fn long_symlink_targets_are_stored_in_a_block() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let open_device = |clear| {
+            FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(64)
+                .clear_medium(clear)
+                .build()
+                .unwrap()
+        };
+        let target = format!("/{}", "d/".repeat(100));
+        let mut fs = SFS::create(open_device(true)).unwrap();
+
+        fs.symlink(&target, "/long").unwrap();
+        fs.symlink("/short", "/short").unwrap();
+        assert_eq!(fs.lstat("/long").unwrap().blocks(), 1);
+        drop(fs);
+
+        let mut fs = SFS::from_block_storage(open_device(false)).unwrap();
+        assert_eq!(fs.readlink("/long").unwrap(), PathBuf::from(&target));
+        assert_eq!(fs.readlink("/short").unwrap(), PathBuf::from("/short"));
+        match fs
+            .symlink("x".repeat(BLOCK_SIZE + 1), "/too_long")
+            .unwrap_err()
+        {
+            SFSError::NameTooLong => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn paths_follow_symlinks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+        let fd = fs
+            .open("/a/b/foo", OpenMode::CREATE | OpenMode::WO)
+            .unwrap();
+        fs.write(fd, b"contents").unwrap();
+        fs.symlink("/a/b", "/abs").unwrap();
+        fs.symlink("b/foo", "/a/rel").unwrap();
+        fs.symlink("../rel", "/a/b/up").unwrap();
+        fs.symlink("/abs/up", "/chain").unwrap();
+
+        assert_eq!(read_all(&mut fs, "/abs/foo"), b"contents");
+        assert_eq!(read_all(&mut fs, "/a/rel"), b"contents");
+        assert_eq!(read_all(&mut fs, "/chain"), b"contents");
+        assert!(fs.stat("/chain").unwrap().is_file());
+        assert!(fs.lstat("/chain").unwrap().is_symlink());
+        fs.open("/abs/created", OpenMode::CREATE).unwrap();
+        assert!(fs.stat("/a/b/created").is_ok());
+    }
+
+    #[test]
+    fn symlink_loops_are_detected() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.symlink("/b", "/a").unwrap();
+        fs.symlink("/a", "/b").unwrap();
+        fs.symlink("self", "/self").unwrap();
+
+        for path in ["/a", "/self", "/a/foo"].iter() {
+            match fs.open(path, OpenMode::RO).unwrap_err() {
+                SFSError::SymlinkLoop => (),
+                _ => panic!("Unexpected error type."),
+            }
+        }
+    }
+
+    #[test]
+    fn nofollow_refuses_to_open_symlinks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.symlink("/foo", "/link").unwrap();
+
+        match fs.open("/link", OpenMode::NOFOLLOW).unwrap_err() {
+            SFSError::SymlinkLoop => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert!(fs.open("/foo", OpenMode::NOFOLLOW).is_ok());
+    }
+
+    #[test]
+    fn nofollow_follows_symlinks_with_trailing_slash() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/dir").unwrap();
+        fs.symlink("/dir", "/link").unwrap();
+
+        let fd = fs
+            .open("/link/", OpenMode::NOFOLLOW | OpenMode::DIRECTORY)
+            .unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().ino(), fs.stat("/dir").unwrap().ino());
+        match fs.open("/link", OpenMode::NOFOLLOW).unwrap_err() {
+            SFSError::SymlinkLoop => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn dangling_symlinks_can_be_read_and_removed() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let free_inodes = fs.super_block.free_inodes_count;
+        fs.symlink("/missing", "/link").unwrap();
+
+        match fs.stat("/link").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert_eq!(fs.readlink("/link").unwrap(), PathBuf::from("/missing"));
+        match fs.readlink("/").unwrap_err() {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+
+        fs.unlink("/link").unwrap();
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+    }
+
+    #[test]
+    fn dot_components_are_resolved() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+        fs.open("/a/b/foo", OpenMode::CREATE).unwrap();
+        let foo = fs.stat("/a/b/foo").unwrap().ino();
+        let b = fs.stat("/a/b").unwrap().ino();
+
+        assert_eq!(fs.stat("/a/./b/../b//foo").unwrap().ino(), foo);
+        assert_eq!(fs.stat("//a/b/.").unwrap().ino(), b);
+        assert_eq!(fs.stat("/a/..").unwrap().ino(), 0);
+        assert_eq!(fs.stat("/../..").unwrap().ino(), 0);
+        match fs.stat("/a/b/foo/.").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.rmdir("/a/b/.").unwrap_err() {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn trailing_slash_requires_a_directory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.mkdir("/dir/").unwrap();
+        fs.symlink("/dir", "/link").unwrap();
+
+        assert!(fs.stat("/dir/").unwrap().is_dir());
+        assert!(fs.lstat("/link/").unwrap().is_dir());
+        assert!(fs.lstat("/link").unwrap().is_symlink());
+        match fs.stat("/foo/").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.unlink("/foo/").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.open("/bar/", OpenMode::CREATE).unwrap_err() {
+            SFSError::IsADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn trailing_slash_on_a_new_name_requires_a_directory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/f", OpenMode::CREATE).unwrap();
+        fs.mkdir("/d").unwrap();
+
+        match fs.rename("/f", "/i/").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.link("/f", "/g/").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.symlink("/f", "/h/").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        for path in ["/i", "/g", "/h"].iter() {
+            match fs.lstat(path).unwrap_err() {
+                SFSError::DoesNotExist => (),
+                _ => panic!("Unexpected error type."),
+            }
+        }
+        assert!(fs.stat("/f").unwrap().is_file());
+
+        // Directories may still take a new name ending in a slash.
+        fs.rename("/d", "/e/").unwrap();
+        assert!(fs.stat("/e").unwrap().is_dir());
+    }
+
+    #[test]
+    fn overlong_names_and_paths_return_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let long_name = format!("/{}", "a".repeat(256));
+        let long_path = "/a".repeat(MAX_PATH_LEN / 2);
+
+        for path in [long_name, long_path].iter() {
+            match fs.stat(path).unwrap_err() {
+                SFSError::NameTooLong => (),
+                _ => panic!("Unexpected error type."),
+            }
+        }
+    }
+
+    #[test]
+    fn relative_paths_resolve_from_working_directory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        fs.mkdir("a").unwrap();
+        fs.chdir("a").unwrap();
+        fs.open("foo", OpenMode::CREATE).unwrap();
+        fs.mkdir("./b").unwrap();
+
+        let foo = fs.stat("/a/foo").unwrap().ino();
+        assert_eq!(fs.stat("../a/b/../foo").unwrap().ino(), foo);
+        assert_eq!(fs.stat(".").unwrap().ino(), fs.stat("/a").unwrap().ino());
+        match fs.stat("").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.chdir("foo").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+
+        let root = fs.open("/", OpenMode::DIRECTORY).unwrap();
+        fs.fchdir(root).unwrap();
+        assert_eq!(fs.stat("a/foo").unwrap().ino(), foo);
+    }
+
+    #[test]
+    fn at_operations_resolve_from_directory_descriptor() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        let dirfd = fs.open("/a", OpenMode::DIRECTORY).unwrap();
+
+        fs.mkdirat(dirfd, "sub").unwrap();
+        let fd = fs.openat(dirfd, "sub/foo", OpenMode::CREATE).unwrap();
+        fs.openat(dirfd, "/bar", OpenMode::CREATE).unwrap();
+
+        let foo = fs.stat("/a/sub/foo").unwrap().ino();
+        assert_eq!(fs.fstatat(dirfd, "sub/foo").unwrap().ino(), foo);
+        assert!(fs.stat("/bar").is_ok());
+        fs.unlinkat(dirfd, "sub/foo").unwrap();
+        assert!(fs.stat("/a/sub/foo").is_err());
+
+        match fs.fstatat(fd, "foo").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.fstatat(99, "foo").unwrap_err() {
+            SFSError::BadDescriptor => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn link_operations_resolve_from_directory_descriptors() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.open("/a/foo", OpenMode::CREATE).unwrap();
+        let a = fs.open("/a", OpenMode::DIRECTORY).unwrap();
+        let b = fs.open("/b", OpenMode::DIRECTORY).unwrap();
+        let foo = fs.stat("/a/foo").unwrap().ino();
+
+        fs.linkat(a, "foo", b, "hard").unwrap();
+        assert_eq!(fs.stat("/b/hard").unwrap().ino(), foo);
+        fs.symlinkat("../a/foo", b, "soft").unwrap();
+        assert_eq!(fs.readlinkat(b, "soft").unwrap(), PathBuf::from("../a/foo"));
+        assert_eq!(fs.stat("/b/soft").unwrap().ino(), foo);
+        fs.renameat(b, "hard", a, "moved").unwrap();
+        assert_eq!(fs.stat("/a/moved").unwrap().ino(), foo);
+        assert!(fs.stat("/b/hard").is_err());
+
+        let file = fs.open("/a/foo", OpenMode::RO).unwrap();
+        match fs.renameat(file, "moved", a, "again").unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.linkat(a, "foo", 99, "foo").unwrap_err() {
+            SFSError::BadDescriptor => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn removed_working_directory_is_freed_once_left() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let free_inodes = fs.super_block.free_inodes_count;
+        fs.mkdir("/a").unwrap();
+        fs.chdir("/a").unwrap();
+
+        fs.rmdir("/a").unwrap();
+
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes - 1);
+        match fs.open("foo", OpenMode::CREATE).unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+        fs.chdir("/").unwrap();
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+    }
+
+    #[test]
+    fn unlinked_file_stays_readable_until_closed() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        let inum = fs.handles.get(fd).unwrap().inum;
+
+        fs.unlink("/foo").unwrap();
+
+        assert!(fs.open("/foo", OpenMode::RO).is_err());
+        let mut buf = [0; 3];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+
+        fs.close(fd).unwrap();
+        assert!(fs.inodes.get(inum).is_none());
+    }
+
+    #[test]
+    fn unlink_missing_file_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        match fs.unlink("/foo").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn unlink_directory_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+
+        match fs.unlink("/a").unwrap_err() {
+            SFSError::IsADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn rename_within_directory_moves_entry() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        let inum = fs.handles.get(fd).unwrap().inum;
+
+        fs.rename("/foo", "/bar").unwrap();
+
+        assert_eq!(fs.lookup(0, OsStr::new("foo")).unwrap(), None);
+        assert_eq!(fs.lookup(0, OsStr::new("bar")).unwrap(), Some(inum));
+    }
+
+    #[test]
+    fn rename_across_directories_moves_entry() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.open("/a/foo", OpenMode::CREATE).unwrap();
+
+        fs.rename("/a/foo", "/b/bar").unwrap();
+
+        assert!(fs.open("/a/foo", OpenMode::RO).is_err());
+        assert!(fs.open("/b/bar", OpenMode::RO).is_ok());
+    }
+
+    #[test]
+    fn rename_over_existing_file_replaces_it() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let tmp = fs.open("/tmp", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(tmp, b"new").unwrap();
+        let old = fs.open("/out", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(old, b"old").unwrap();
+        let old_inum = fs.handles.get(old).unwrap().inum;
+        fs.close(old).unwrap();
+
+        fs.rename("/tmp", "/out").unwrap();
+
+        assert!(fs.inodes.get(old_inum).is_none());
+        let fd = fs.open("/out", OpenMode::RO).unwrap();
+        let mut buf = [0; 3];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"new");
+    }
+
+    #[test]
+    fn rename_directory_updates_parent_links() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.mkdir("/a/c").unwrap();
+        let a = fs.lookup(0, OsStr::new("a")).unwrap().unwrap();
+        let b = fs.lookup(0, OsStr::new("b")).unwrap().unwrap();
+
+        fs.rename("/a/c", "/b/c").unwrap();
+
+        let c = fs.lookup(b, OsStr::new("c")).unwrap().unwrap();
+        assert_eq!(fs.lookup(c, OsStr::new("..")).unwrap(), Some(b));
+        assert_eq!(fs.inodes.get(a).unwrap().links_count, 2);
+        assert_eq!(fs.inodes.get(b).unwrap().links_count, 3);
+    }
+
+    #[test]
+    fn rename_directory_into_own_descendant_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+
+        assert!(fs.rename("/a", "/a/b/c").is_err());
+        assert!(fs.rename("/a", "/a/c").is_err());
+        assert!(fs.open("/a/b", OpenMode::DIRECTORY).is_ok());
+    }
+
+    #[test]
+    fn rename_directory_over_non_empty_directory_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.open("/b/foo", OpenMode::CREATE).unwrap();
+
+        match fs.rename("/a", "/b").unwrap_err() {
+            SFSError::DirectoryNotEmpty => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn rename_missing_file_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        match fs.rename("/foo", "/bar").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn stat_reports_file_metadata() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; BLOCK_SIZE + 1]).unwrap();
+
+        let meta = fs.stat("/foo").unwrap();
+
+        assert!(meta.is_file());
+        assert_eq!(meta.file_type(), FileType::RegularFile);
+        assert_eq!(meta.len(), BLOCK_SIZE as u64 + 1);
+        assert_eq!(meta.blocks(), 2);
+        assert_eq!(meta.nlink(), 1);
+        assert_eq!(meta.uid(), 0);
+        assert_eq!(meta.permissions().mode(), 0o644);
+        assert!(!meta.permissions().readonly());
+        assert_eq!(meta.ino(), fs.handles.get(fd).unwrap().inum);
+        assert_eq!(fs.fstat(fd).unwrap(), meta);
+    }
+
+    #[test]
+    fn stat_reports_directory_metadata() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+
+        let root = fs.stat("/").unwrap();
+        let meta = fs.stat("/a").unwrap();
+
+        assert!(root.is_dir());
+        assert_eq!(root.ino(), 0);
+        assert_eq!(root.nlink(), 3);
+        assert!(meta.is_dir());
+        assert_eq!(meta.mode(), 0o40755);
+    }
+
+    #[test]
+    fn stat_missing_file_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        match fs.stat("/foo").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert!(fs.fstat(3).is_err());
+    }
+
+    #[test]
+    fn created_files_survive_reopening_filesystem() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        let fd = fs.open("/a/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        fs.open("/bar", OpenMode::CREATE).unwrap();
+        let baz = fs.open("/baz", OpenMode::CREATE).unwrap();
+        fs.close(baz).unwrap();
+        fs.unlink("/baz").unwrap();
+        let free_blocks = fs.super_block.free_blocks_count;
+        let free_inodes = fs.super_block.free_inodes_count;
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+
+        assert_eq!(fs.super_block.free_blocks_count, free_blocks);
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+        assert!(fs.stat("/a").unwrap().is_dir());
+        assert!(fs.stat("/bar").unwrap().is_file());
+        assert!(fs.stat("/baz").is_err());
+        let fd = fs.open("/a/foo", OpenMode::RO).unwrap();
+        let mut buf = [0; 5];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+
+        // New allocations must not hand out blocks or inodes already in use.
+        let fd = fs.open("/qux", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"world").unwrap();
+        let fd = fs.open("/a/foo", OpenMode::RO).unwrap();
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn sync_writes_changes_without_dropping() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.sync().unwrap();
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut reopened = SFS::from_block_storage(dev).unwrap();
+        assert!(reopened.stat("/foo").is_ok());
+        drop(fs);
+    }
+
+    #[test]
+    fn files_unlinked_while_open_are_freed_on_drop() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        let free_inodes = fs.super_block.free_inodes_count;
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.unlink("/foo").unwrap();
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let fs = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+        assert_eq!(fs.inodes.total_nodes(), 1);
+    }
+
+    #[test]
+    fn formatted_geometry_is_read_back_on_mount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(256)
+            .build()
+            .unwrap();
+        let options = FormatOptions::new()
+            .with_total_blocks(256)
+            .with_inode_count(200);
+        let mut fs = SFS::format(dev, &options).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        let super_block = fs.super_block;
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(256)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+
+        assert_eq!(fs.super_block, super_block);
+        assert_eq!(fs.super_block.inodes_count, 208);
+        assert_eq!(fs.super_block.total_blocks_count, 256);
+        assert_eq!(fs.stat("/foo").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn volumes_beyond_one_bitmap_block_track_every_block() {
+        let blocks = BLOCK_SIZE * 8 + 1024;
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        // Leave the device sparse rather than writing out every block.
+        disk.as_file()
+            .set_len((blocks * BLOCK_SIZE) as u64)
+            .unwrap();
+        let open_device = || {
+            FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(blocks)
+                .clear_medium(false)
+                .build()
+                .unwrap()
+        };
+        let options = FormatOptions::new().with_total_blocks(blocks as u32);
+        let mut fs = SFS::format(open_device(), &options).unwrap();
+        assert_eq!(fs.super_block.data_bitmap_blocks, 2);
+
+        // Fill the blocks tracked by the first bitmap block so the next allocation lands past it.
+        for block in 0..BLOCK_SIZE * 8 {
+            fs.data_map.set_reserved(block);
+        }
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"far away").unwrap();
+        let blocknr = fs.inodes.get(1).unwrap().blocks[0] as usize;
+        assert!(blocknr >= BLOCK_SIZE * 8);
+        drop(fs);
+
+        let mut fs = SFS::from_block_storage(open_device()).unwrap();
+        assert_eq!(fs.data_map.get(blocknr), State::Used);
+        assert_eq!(fs.data_map.get(blocknr + 1), State::Free);
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        let mut buf = [0; 8];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, b"far away");
+    }
+
+    #[test]
+    fn images_without_wide_inodes_are_upgraded_on_mount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"abc").unwrap();
+        fs.super_block.feature_flags &= !FEATURE_WIDE_INODES;
+        fs.dirty_super_block = true;
+        drop(fs);
+
+        let open_device = || {
+            FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(64)
+                .clear_medium(false)
+                .build()
+                .unwrap()
+        };
+        let fs = SFS::from_block_storage(open_device()).unwrap();
+        assert!(fs.super_block.has_feature(FEATURE_WIDE_INODES));
+        drop(fs);
+
+        let mut fs = SFS::from_block_storage(open_device()).unwrap();
+        assert!(fs.super_block.has_feature(FEATURE_WIDE_INODES));
+        assert_eq!(fs.stat("/foo").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn text_directories_are_converted_on_mount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.open("/a/foo", OpenMode::CREATE).unwrap();
+        let foo = fs.resolve(0, Path::new("/a/foo")).unwrap();
+        let a = fs.resolve(0, Path::new("/a")).unwrap();
+        // Rewrite the directories the way they were stored before entry records.
+        for (dir, text) in [
+            (0, format!("0:.\n0:..\n{}:a\n", a)),
+            (a, format!("{}:.\n0:..\n{}:foo\n", a, foo)),
+        ]
+        .iter()
+        {
+            fs.release_blocks(*dir).unwrap();
+            fs.write_inode(*dir, 0, text.as_bytes()).unwrap();
+        }
+        fs.super_block.feature_flags &= !FEATURE_DIR_RECORDS;
+        fs.dirty_super_block = true;
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        assert!(fs.super_block.has_feature(FEATURE_DIR_RECORDS));
+        assert_eq!(fs.stat("/a").unwrap().len(), BLOCK_SIZE as u64);
+        assert_eq!(fs.stat("/a/foo").unwrap().ino(), foo);
+        let names: Vec<OsString> = fs
+            .read_dir("/a")
+            .unwrap()
+            .map(|entry| entry.unwrap().name)
+            .collect();
+        assert_eq!(names, vec![".", "..", "foo"]);
+    }
+
+    fn create_large_test_fs(blocks: u32, inodes: u32) -> SFS<FileBlockEmulator> {
+        let dev = FileBlockEmulatorBuilder::from(tempfile::tempfile().unwrap())
+            .with_block_size(blocks as usize)
+            .build()
+            .unwrap();
+        let options = FormatOptions::new()
+            .with_total_blocks(blocks)
+            .with_inode_count(inodes);
+        SFS::format(dev, &options).unwrap()
+    }
+
+    #[test]
+    fn directories_outgrowing_a_block_are_indexed() {
+        let mut fs = create_large_test_fs(1024, 2048);
+        fs.mkdir("/cache").unwrap();
+        let dir = fs.resolve(0, Path::new("/cache")).unwrap();
+        let mut indexed = false;
+        for i in 0..1500 {
+            fs.open(format!("/cache/entry-{}", i), OpenMode::CREATE)
+                .unwrap();
+            // Once indexed a directory stays indexed.
+            assert!(fs.is_indexed(dir) || !indexed);
+            indexed = fs.is_indexed(dir);
+        }
+        assert!(indexed);
+        for i in (0..1500).step_by(2) {
+            fs.unlink(format!("/cache/entry-{}", i)).unwrap();
+        }
+
+        for i in 0..1500 {
+            let found = fs.stat(format!("/cache/entry-{}", i)).is_ok();
+            assert_eq!(found, i % 2 == 1);
+        }
+        assert_eq!(fs.read_dir("/cache").unwrap().count(), 750 + 2);
+        assert_eq!(fs.lookup(dir, OsStr::new("..")).unwrap(), Some(0));
+        assert!(
+            IndexRoot::parse(&fs.read_dir_block(dir, 0).unwrap())
+                .unwrap()
+                .entries
+                .len()
+                > 1
+        );
+    }
+
+    #[test]
+    fn indexed_directories_persist_across_remount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let open_device = |clear| {
+            FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(512)
+                .clear_medium(clear)
+                .build()
+                .unwrap()
+        };
+        let options = FormatOptions::new()
+            .with_total_blocks(512)
+            .with_inode_count(1024);
+        let mut fs = SFS::format(open_device(true), &options).unwrap();
+        for i in 0..800 {
+            fs.open(format!("/{}", i), OpenMode::CREATE).unwrap();
+        }
+        assert!(fs.is_indexed(0));
+        drop(fs);
+
+        let mut fs = SFS::from_block_storage(open_device(false)).unwrap();
+        for i in 0..800 {
+            assert!(fs.stat(format!("/{}", i)).unwrap().is_file());
+        }
+        fs.open("/800", OpenMode::CREATE).unwrap();
+        assert_eq!(fs.read_dir("/").unwrap().count(), 801 + 2);
+    }
+
+    #[test]
+    fn directory_entries_keep_their_place() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let names = |fs: &mut SFS<FileBlockEmulator>| -> Vec<OsString> {
+            fs.read_dir("/")
+                .unwrap()
+                .map(|entry| entry.unwrap().name)
+                .collect()
+        };
+        for name in ["a", "b", "c", "d"].iter() {
+            fs.open(format!("/{}", name), OpenMode::CREATE).unwrap();
+        }
+        assert_eq!(names(&mut fs), vec![".", "..", "a", "b", "c", "d"]);
+
+        fs.unlink("/b").unwrap();
+        fs.open("/e", OpenMode::CREATE).unwrap();
+        fs.rename("/c", "/a").unwrap();
+
+        assert_eq!(names(&mut fs), vec![".", "..", "a", "e", "d"]);
+        assert_eq!(fs.stat("/").unwrap().len(), BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn full_index_root_moves_into_interior_node() {
+        let mut fs = create_large_test_fs(4096, 8192);
+        let name = |i: usize| format!("/{:0>250}", i);
+        let mut created = 0;
+        let levels = |fs: &mut SFS<FileBlockEmulator>| {
+            IndexRoot::parse(&fs.read_dir_block(0, 0).unwrap())
+                .unwrap()
+                .levels
+        };
+        while !fs.is_indexed(0) || levels(&mut fs) == 0 {
+            fs.open(name(created), OpenMode::CREATE).unwrap();
+            created += 1;
+        }
+        for _ in 0..100 {
+            fs.open(name(created), OpenMode::CREATE).unwrap();
+            created += 1;
+        }
+
+        for i in 0..created {
+            assert!(fs.stat(name(i)).is_ok());
+        }
+        assert_eq!(fs.read_dir("/").unwrap().count(), created + 2);
+    }
+
+    #[test]
+    fn renaming_indexed_directory_updates_its_parent_entry() {
+        let mut fs = create_large_test_fs(1024, 1024);
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        for i in 0..300 {
+            fs.open(format!("/a/{:0>40}", i), OpenMode::CREATE).unwrap();
+        }
+        let a = fs.resolve(0, Path::new("/a")).unwrap();
+        let b = fs.resolve(0, Path::new("/b")).unwrap();
+        assert!(fs.is_indexed(a));
+
+        fs.rename("/a", "/b/a").unwrap();
+
+        assert_eq!(fs.lookup(a, OsStr::new("..")).unwrap(), Some(b));
+        assert!(fs.stat(format!("/b/a/{:0>40}", 299)).unwrap().is_file());
+        match fs.rmdir("/b/a").unwrap_err() {
+            SFSError::DirectoryNotEmpty => (),
+            _ => panic!("Unexpected error type."),
+        }
+        for i in 0..300 {
+            fs.unlink(format!("/b/a/{:0>40}", i)).unwrap();
+        }
+        fs.rmdir("/b/a").unwrap();
+        assert_eq!(fs.stat("/b").unwrap().nlink(), 2);
+    }
+
+    #[test]
+    fn can_fill_every_configured_inode() {
+        let dev = FileBlockEmulatorBuilder::from(tempfile::tempfile().unwrap())
+            .with_block_size(256)
+            .build()
+            .unwrap();
+        let options = FormatOptions::new()
+            .with_total_blocks(256)
+            .with_inode_count(100);
+        let mut fs = SFS::format(dev, &options).unwrap();
+
+        // The root directory holds the first inode.
+        for i in 1..fs.super_block.inodes_count {
+            fs.open(format!("/{}", i), OpenMode::CREATE).unwrap();
+        }
+        assert_eq!(fs.super_block.free_inodes_count, 0);
+    }
+
+    #[test]
+    fn creating_past_the_last_inode_is_no_space() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/dir").unwrap();
+        for i in 2..fs.super_block.inodes_count {
+            let fd = fs.open(format!("/dir/{}", i), OpenMode::CREATE).unwrap();
+            fs.close(fd).unwrap();
+        }
+
+        match fs.open("/foo", OpenMode::CREATE).unwrap_err() {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.mkdir("/bar").unwrap_err() {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.symlink("/dir", "/baz").unwrap_err() {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert_eq!(fs.super_block.free_inodes_count, 0);
+        match fs.stat("/foo").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+
+        fs.unlink("/dir/2").unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+    }
+
+    #[test]
+    fn mkdir_on_a_full_device_frees_the_inode() {
+        let options = FormatOptions::new().with_reserved_blocks(50);
+        let mut fs = SFS::format(create_test_device(), &options).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; 5 * BLOCK_SIZE]).unwrap();
+
+        let free_inodes = fs.super_block.free_inodes_count;
+        match fs.mkdir("/dir").unwrap_err() {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+        assert_eq!(fs.stat("/").unwrap().nlink(), 2);
+    }
+
+    #[test]
+    fn moving_a_directory_past_the_parent_link_limit_is_refused() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/dir").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.mkdir("/b/dir").unwrap();
+        let b = fs.stat("/b").unwrap().ino();
+        fs.inodes.get_mut(b).unwrap().links_count = std::u16::MAX;
+
+        match fs.rename("/a/dir", "/b/new").unwrap_err() {
+            SFSError::TooManyLinks => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert!(fs.stat("/a/dir").unwrap().is_dir());
+        assert_eq!(fs.stat("/a").unwrap().nlink(), 3);
+        assert_eq!(fs.stat("/b").unwrap().nlink(), u64::from(std::u16::MAX));
+
+        // Taking the place of another directory leaves the count as it was.
+        fs.rename("/a/dir", "/b/dir").unwrap();
+        assert_eq!(fs.stat("/a").unwrap().nlink(), 2);
+        assert_eq!(fs.stat("/b").unwrap().nlink(), u64::from(std::u16::MAX));
+    }
+
+    #[test]
+    fn symlink_on_a_full_device_frees_the_inode() {
+        let options = FormatOptions::new().with_reserved_blocks(50);
+        let mut fs = SFS::format(create_test_device(), &options).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; 5 * BLOCK_SIZE]).unwrap();
+
+        let free_inodes = fs.super_block.free_inodes_count;
+        let target = "/foo".repeat(INLINE_DATA_LEN);
+        match fs.symlink(&target, "/link").unwrap_err() {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+        match fs.lstat("/link").unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn mkdir_past_the_parent_link_limit_is_refused() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.inodes.get_mut(0).unwrap().links_count = std::u16::MAX;
+
+        let free_inodes = fs.super_block.free_inodes_count;
+        match fs.mkdir("/dir").unwrap_err() {
+            SFSError::TooManyLinks => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert_eq!(fs.super_block.free_inodes_count, free_inodes);
+        assert_eq!(fs.stat("/").unwrap().nlink(), u64::from(std::u16::MAX));
+    }
+
+    #[test]
+    fn allocations_stop_at_reserved_blocks() {
+        let options = FormatOptions::new().with_reserved_blocks(50);
+        let mut fs = SFS::format(create_test_device(), &options).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+
+        // 56 data blocks, less one for the root directory and the 50 reserved.
+        assert!(fs.write(fd, &vec![0x11; 5 * BLOCK_SIZE]).is_ok());
+        match fs.write(fd, b"x").unwrap_err() {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn filling_the_device_is_a_short_write() {
+        let options = FormatOptions::new().with_reserved_blocks(50);
+        let mut fs = SFS::format(create_test_device(), &options).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"foo").unwrap();
+
+        // Only the 5 blocks left before the reserved ones can be written.
+        let data = vec![0x11; 8 * BLOCK_SIZE];
+        assert_eq!(fs.write(fd, &data).unwrap(), 5 * BLOCK_SIZE - 3);
+        assert_eq!(fs.fstat(fd).unwrap().len(), 5 * BLOCK_SIZE as u64);
+        match fs.write(fd, &data).unwrap_err() {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+
+        let mut buf = vec![0; 6 * BLOCK_SIZE];
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 5 * BLOCK_SIZE);
+        assert_eq!(&buf[..3], b"foo");
+        assert!(buf[3..5 * BLOCK_SIZE].iter().all(|&b| b == 0x11));
+    }
+
+    #[test]
+    fn empty_write_past_the_end_keeps_the_size() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"foo").unwrap();
+
+        fs.seek(fd, SeekFrom::Start(100)).unwrap();
+        assert_eq!(fs.write(fd, b"").unwrap(), 0);
+        assert_eq!(fs.fstat(fd).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn allocations_search_from_the_first_free_block() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let start = fs.super_block.data_region_start;
+        // The root directory holds the first data block.
+        assert_eq!(fs.super_block.free_list, start + 1);
+
+        let fd = fs.open("/a", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; 3 * BLOCK_SIZE]).unwrap();
+        fs.close(fd).unwrap();
+        let fd = fs.open("/b", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"b").unwrap();
+        assert_eq!(fs.super_block.free_list, start + 5);
+
+        fs.unlink("/a").unwrap();
+        assert_eq!(fs.super_block.free_list, start + 1);
+        let fd = fs.open("/c", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, b"c").unwrap();
+        let inum = fs.fstat(fd).unwrap().ino();
+        assert_eq!(fs.inodes.get(inum).unwrap().blocks[0], start + 1);
+        assert_eq!(fs.super_block.free_list, start + 2);
+    }
+
+    #[test]
+    fn can_create_and_reopen_initialized_filesystem() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        // Initialize the filesystem.
+        SFS::create(dev).unwrap();
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            // Don't reset initialized disk.
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let fs: SFS<FileBlockEmulator> = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(fs.inodes.total_nodes(), 1);
+    }
+
+    #[test]
+    fn can_mount_and_write_to_baseline_images() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let reopen = |clear| {
+            FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(64)
+                .clear_medium(clear)
+                .build()
+                .unwrap()
+        };
+        // The original 64 block layout: a superblock without free counts, empty bitmaps but for
+        // the root inode and a root directory holding no blocks.
+        let mut dev = reopen(true);
+        let mut block = [0; BLOCK_SIZE];
+        for (i, field) in [SB_MAGIC, 80, 56, 0, 0, 80, 0].iter().enumerate() {
+            block[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        dev.write_block(0, &mut block).unwrap();
+        let mut block = [0; BLOCK_SIZE];
+        block[0] = 1;
+        dev.write_block(2, &mut block).unwrap();
+        let mut block = [0; BLOCK_SIZE];
+        block[..2].copy_from_slice(&0x4000_u16.to_le_bytes());
+        dev.write_block(3, &mut block).unwrap();
+
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        // The root directory was rewritten into the first data block.
+        assert_eq!(fs.super_block.free_blocks_count, 55);
+        assert_eq!(fs.super_block.free_inodes_count, 79);
+        assert_eq!(fs.stat("/").unwrap().nlink(), 2);
+        for block in 0..9 {
+            assert_eq!(fs.data_map.get(block), State::Used);
+        }
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        fs.write(fd, &vec![0x11; 2 * BLOCK_SIZE]).unwrap();
+        assert_eq!(fs.super_block.free_blocks_count, 53);
+        drop(fs);
+
+        let mut fs = SFS::from_block_storage(reopen(false)).unwrap();
+        assert_eq!(fs.super_block.free_inodes_count, 78);
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        let mut buf = vec![0; 3 * BLOCK_SIZE];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 2 * BLOCK_SIZE);
+        assert!(buf[..2 * BLOCK_SIZE].iter().all(|&b| b == 0x11));
+    }
+
+    fn switch_user(fs: &mut SFS<FileBlockEmulator>, uid: u32, gid: u32) {
+        fs.set_credentials(Credentials::new(uid, gid)).unwrap();
+    }
+
+    fn assert_denied<V>(result: Result<V, SFSError>) {
+        match result.err() {
+            Some(SFSError::PermissionDenied) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
+    fn assert_not_permitted<V>(result: Result<V, SFSError>) {
+        match result.err() {
+            Some(SFSError::NotPermitted) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
+    #[test]
+    fn new_files_are_owned_by_the_caller() {
+        let options = MountOptions::new().with_credentials(Credentials::new(1000, 100));
+        let mut fs =
+            SFS::format_and_mount(create_test_device(), &FormatOptions::new(), &options).unwrap();
+        assert_eq!(fs.credentials(), &Credentials::new(1000, 100));
+        assert_eq!(fs.stat("/").unwrap().uid(), 0);
+        switch_user(&mut fs, 0, 0);
+        fs.chmod("/", 0o777).unwrap();
+        switch_user(&mut fs, 1000, 100);
+
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.mkdir("/dir").unwrap();
+        fs.symlink("foo", "/link").unwrap();
+
+        for path in ["/foo", "/dir", "/link"].iter() {
+            let meta = fs.lstat(path).unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (1000, 100));
+        }
+    }
+
+    #[test]
+    fn open_checks_read_and_write_permission() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.chown("/foo", Some(1000), Some(100)).unwrap();
+
+        switch_user(&mut fs, 1001, 101);
+        assert!(fs.open("/foo", OpenMode::RO).is_ok());
+        assert_denied(fs.open("/foo", OpenMode::WO));
+        assert_denied(fs.open("/foo", OpenMode::CREATE | OpenMode::RW));
+        assert_denied(fs.truncate("/foo", 0));
+
+        switch_user(&mut fs, 1000, 100);
+        fs.chmod("/foo", 0o600).unwrap();
+        assert!(fs.open("/foo", OpenMode::RW).is_ok());
+        switch_user(&mut fs, 1001, 100);
+        assert_denied(fs.open("/foo", OpenMode::RO));
+    }
+
+    #[test]
+    fn walking_a_directory_needs_search_permission() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/dir").unwrap();
+        fs.open("/dir/foo", OpenMode::CREATE).unwrap();
+        fs.chmod("/dir", 0o744).unwrap();
+
+        switch_user(&mut fs, 1000, 100);
+        assert_denied(fs.stat("/dir/foo"));
+        assert_denied(fs.chdir("/dir"));
+        assert!(fs.read_dir("/dir").is_ok());
+
+        switch_user(&mut fs, 0, 0);
+        fs.chmod("/dir", 0o711).unwrap();
+        switch_user(&mut fs, 1000, 100);
+        assert!(fs.stat("/dir/foo").is_ok());
+        assert_denied(fs.read_dir("/dir"));
+    }
+
+    #[test]
+    fn changing_entries_needs_write_permission_on_the_directory() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/dir").unwrap();
+        fs.open("/dir/foo", OpenMode::CREATE).unwrap();
+        fs.chmod("/dir/foo", 0o666).unwrap();
+
+        switch_user(&mut fs, 1000, 100);
+        assert_denied(fs.open("/dir/new", OpenMode::CREATE));
+        assert_denied(fs.mkdir("/dir/new"));
+        assert_denied(fs.symlink("foo", "/dir/new"));
+        assert_denied(fs.link("/dir/foo", "/dir/new"));
+        assert_denied(fs.unlink("/dir/foo"));
+        assert_denied(fs.rename("/dir/foo", "/dir/new"));
+        // Opening an existing file only takes permission on the file.
+        assert!(fs.open("/dir/foo", OpenMode::CREATE | OpenMode::RW).is_ok());
+    }
+
+    #[test]
+    fn sticky_directories_only_let_owners_remove_entries() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/tmp").unwrap();
+        fs.chmod("/tmp", 0o1777).unwrap();
+        switch_user(&mut fs, 1000, 100);
+        fs.open("/tmp/mine", OpenMode::CREATE).unwrap();
+
+        switch_user(&mut fs, 1001, 100);
+        fs.open("/tmp/theirs", OpenMode::CREATE).unwrap();
+        assert_not_permitted(fs.unlink("/tmp/mine"));
+        assert_not_permitted(fs.rename("/tmp/mine", "/tmp/moved"));
+        assert_not_permitted(fs.rename("/tmp/theirs", "/tmp/mine"));
+
+        switch_user(&mut fs, 1000, 100);
+        fs.unlink("/tmp/mine").unwrap();
+        switch_user(&mut fs, 0, 0);
+        fs.unlink("/tmp/theirs").unwrap();
+    }
+
+    #[test]
+    fn moving_a_directory_needs_write_permission_on_it() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        for dir in ["/a", "/b", "/a/dir"].iter() {
+            fs.mkdir(dir).unwrap();
+        }
+        fs.chmod("/a", 0o777).unwrap();
+        fs.chmod("/b", 0o777).unwrap();
+
+        switch_user(&mut fs, 1000, 100);
+        assert_denied(fs.rename("/a/dir", "/b/dir"));
+        fs.rename("/a/dir", "/a/renamed").unwrap();
+    }
+
+    #[test]
+    fn setgid_directories_pass_on_their_group() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/shared").unwrap();
+        fs.chown("/shared", None, Some(50)).unwrap();
+        fs.chmod("/shared", 0o2777).unwrap();
+
+        switch_user(&mut fs, 1000, 100);
+        fs.open("/shared/foo", OpenMode::CREATE).unwrap();
+        fs.mkdir("/shared/sub").unwrap();
+        fs.mkdir("/shared/sub/deeper").unwrap();
+
+        let file = fs.stat("/shared/foo").unwrap();
+        assert_eq!((file.uid(), file.gid()), (1000, 50));
+        assert_eq!(file.permissions().mode(), 0o644);
+        for dir in ["/shared/sub", "/shared/sub/deeper"].iter() {
+            let meta = fs.stat(dir).unwrap();
+            assert_eq!(meta.gid(), 50);
+            assert_eq!(meta.permissions().mode(), 0o2755);
+        }
+    }
+
+    #[test]
+    fn only_owners_may_change_mode_and_group() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.chown("/foo", Some(1000), Some(100)).unwrap();
+        fs.chmod("/foo", 0o4755).unwrap();
+
+        switch_user(&mut fs, 1001, 100);
+        assert_not_permitted(fs.chmod("/foo", 0o777));
+        assert_not_permitted(fs.chown("/foo", None, Some(100)));
+
+        fs.set_credentials(Credentials::new(1000, 200).with_groups(vec![300]))
+            .unwrap();
+        assert_not_permitted(fs.chown("/foo", Some(1001), None));
+        assert_not_permitted(fs.chown("/foo", None, Some(400)));
+        fs.chown("/foo", None, Some(300)).unwrap();
+        let meta = fs.stat("/foo").unwrap();
+        assert_eq!(meta.gid(), 300);
+        assert_eq!(meta.permissions().mode(), 0o755);
+
+        assert_not_permitted(fs.chown("/foo", None, Some(100)));
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        fs.fchmod(fd, 0o2644).unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().permissions().mode(), 0o2644);
+        fs.fchown(fd, None, Some(200)).unwrap();
+        fs.fchmod(fd, 0o2644).unwrap();
+        assert_eq!(fs.fstat(fd).unwrap().mode(), 0o102_644);
+    }
+
+    #[test]
+    fn ids_wider_than_stored_are_rejected() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        match fs.chown("/foo", Some(70_000), None).unwrap_err() {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.set_credentials(Credentials::new(0, 70_000)).unwrap_err() {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn only_owners_may_set_explicit_times() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.chown("/foo", Some(1000), Some(100)).unwrap();
+        fs.chmod("/foo", 0o666).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1_000);
+
+        switch_user(&mut fs, 1001, 100);
+        assert_not_permitted(fs.utimens("/foo", TimeUpdate::To(time), TimeUpdate::Omit));
+        assert_not_permitted(fs.utimens("/foo", TimeUpdate::Now, TimeUpdate::To(time)));
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        assert_not_permitted(fs.futimens(fd, TimeUpdate::To(time), TimeUpdate::To(time)));
+
+        switch_user(&mut fs, 1000, 100);
+        fs.chmod("/foo", 0o400).unwrap();
+        fs.utimens("/foo", TimeUpdate::Omit, TimeUpdate::To(time))
+            .unwrap();
+        assert_eq!(fs.stat("/foo").unwrap().modified(), time);
+    }
+
+    #[test]
+    fn setting_times_to_now_needs_write_permission() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.chown("/foo", Some(1000), Some(100)).unwrap();
+
+        switch_user(&mut fs, 1001, 100);
+        assert_denied(fs.utimens("/foo", TimeUpdate::Now, TimeUpdate::Now));
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        assert_denied(fs.futimens(fd, TimeUpdate::Omit, TimeUpdate::Now));
+        assert!(fs
+            .utimens("/foo", TimeUpdate::Omit, TimeUpdate::Omit)
+            .is_ok());
+
+        switch_user(&mut fs, 1000, 100);
+        fs.chmod("/foo", 0o664).unwrap();
+        switch_user(&mut fs, 1001, 100);
+        fs.utimens("/foo", TimeUpdate::Now, TimeUpdate::Now)
+            .unwrap();
+    }
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    struct FakeClock(Arc<std::sync::Mutex<SystemTime>>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self(Arc::new(std::sync::Mutex::new(
+                UNIX_EPOCH + Duration::from_secs(1_000_000),
+            )))
+        }
+
+        fn advance(&self, by: Duration) -> SystemTime {
+            let mut now = self.0.lock().unwrap();
+            *now += by;
+            *now
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn mount_with_clock(atime: AtimePolicy) -> (SFS<FileBlockEmulator>, FakeClock) {
+        let clock = FakeClock::new();
+        let options = MountOptions::new()
+            .with_clock(clock.clone())
+            .with_atime(atime);
+        let fs =
+            SFS::format_and_mount(create_test_device(), &FormatOptions::new(), &options).unwrap();
+        (fs, clock)
+    }
+
+    #[test]
+    fn create_stamps_file_and_parent_times() {
+        let (mut fs, clock) = mount_with_clock(AtimePolicy::Relatime);
+        let start = clock.now();
+        fs.mkdir("/a").unwrap();
+
+        let later = clock.advance(Duration::from_secs(5));
+        fs.open("/a/foo", OpenMode::CREATE).unwrap();
+
+        let file = fs.stat("/a/foo").unwrap();
+        assert_eq!(file.created(), later);
+        assert_eq!(file.modified(), later);
+        assert_eq!(file.accessed(), later);
+        assert_eq!(file.changed(), later);
+        let parent = fs.stat("/a").unwrap();
+        assert_eq!(parent.created(), start);
+        assert_eq!(parent.modified(), later);
+        assert_eq!(parent.changed(), later);
+    }
+
+    #[test]
+    fn write_updates_modification_and_change_times() {
+        let (mut fs, clock) = mount_with_clock(AtimePolicy::Relatime);
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        let created = clock.now();
+
+        let later = clock.advance(Duration::from_millis(1500));
+        fs.write(fd, b"hello").unwrap();
+
+        let meta = fs.stat("/foo").unwrap();
+        assert_eq!(meta.created(), created);
+        assert_eq!(meta.accessed(), created);
+        assert_eq!(meta.modified(), later);
+        assert_eq!(meta.changed(), later);
+    }
+
+    #[test]
+    fn strictatime_updates_access_time_on_every_read() {
+        let (mut fs, clock) = mount_with_clock(AtimePolicy::Strict);
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"hello").unwrap();
+
+        let mut buf = [0; 5];
+        for _ in 0..2 {
+            let later = clock.advance(Duration::from_secs(1));
+            fs.seek(fd, SeekFrom::Start(0)).unwrap();
+            fs.read(fd, &mut buf).unwrap();
+            assert_eq!(fs.stat("/foo").unwrap().accessed(), later);
+        }
+    }
+
+    #[test]
+    fn relatime_skips_reads_until_file_changes_or_a_day_passes() {
+        let (mut fs, clock) = mount_with_clock(AtimePolicy::Relatime);
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        fs.write(fd, b"hello").unwrap();
+        let mut buf = [0; 5];
+
+        // The access time is not newer than the modification, the first read updates it.
+        let first_read = clock.advance(Duration::from_secs(1));
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(fs.stat("/foo").unwrap().accessed(), first_read);
+
+        clock.advance(Duration::from_secs(60));
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(fs.stat("/foo").unwrap().accessed(), first_read);
+
+        let next_day = clock.advance(Duration::from_secs(24 * 60 * 60));
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(fs.stat("/foo").unwrap().accessed(), next_day);
+    }
+
+    #[test]
+    fn noatime_never_updates_access_time() {
+        let (mut fs, clock) = mount_with_clock(AtimePolicy::NoAtime);
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::RW).unwrap();
+        let created = clock.now();
+        fs.write(fd, b"hello").unwrap();
+
+        clock.advance(Duration::from_secs(2 * 24 * 60 * 60));
+        fs.seek(fd, SeekFrom::Start(0)).unwrap();
+        fs.read(fd, &mut [0; 5]).unwrap();
+        fs.read_dir("/").unwrap();
+
+        assert_eq!(fs.stat("/foo").unwrap().accessed(), created);
+        assert_eq!(fs.stat("/").unwrap().accessed(), created);
+    }
+
+    #[test]
+    fn utimens_sets_requested_times() {
+        let (mut fs, clock) = mount_with_clock(AtimePolicy::Relatime);
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        let created = clock.now();
+        let past = UNIX_EPOCH + Duration::new(12_345, 678);
+
+        let now = clock.advance(Duration::from_secs(10));
+        fs.utimens("/foo", TimeUpdate::To(past), TimeUpdate::Omit)
+            .unwrap();
+
+        let meta = fs.stat("/foo").unwrap();
+        assert_eq!(meta.accessed(), past);
+        assert_eq!(meta.modified(), created);
+        assert_eq!(meta.changed(), now);
+
+        let later = clock.advance(Duration::from_secs(10));
+        let fd = fs.open("/foo", OpenMode::RO).unwrap();
+        fs.futimens(fd, TimeUpdate::Omit, TimeUpdate::Now).unwrap();
+        fs.futimens(fd, TimeUpdate::Omit, TimeUpdate::Omit).unwrap();
+
+        let meta = fs.stat("/foo").unwrap();
+        assert_eq!(meta.accessed(), past);
+        assert_eq!(meta.modified(), later);
+        assert_eq!(meta.changed(), later);
+    }
+
+    #[test]
+    fn utimens_before_epoch_returns_error() {
+        let (mut fs, _) = mount_with_clock(AtimePolicy::Relatime);
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        match fs
+            .utimens("/foo", TimeUpdate::Now, TimeUpdate::To(before_epoch))
+            .unwrap_err()
+        {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn rename_and_unlink_update_directory_times() {
+        let (mut fs, clock) = mount_with_clock(AtimePolicy::Relatime);
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.open("/a/foo", OpenMode::CREATE).unwrap();
+
+        let renamed = clock.advance(Duration::from_secs(1));
+        fs.rename("/a/foo", "/b/foo").unwrap();
+
+        assert_eq!(fs.stat("/a").unwrap().modified(), renamed);
+        assert_eq!(fs.stat("/b").unwrap().modified(), renamed);
+        assert_eq!(fs.stat("/b/foo").unwrap().changed(), renamed);
+
+        let unlinked = clock.advance(Duration::from_secs(1));
+        fs.unlink("/b/foo").unwrap();
+
+        assert_eq!(fs.stat("/b").unwrap().modified(), unlinked);
+        assert_eq!(fs.stat("/a").unwrap().modified(), renamed);
+    }
+
+    #[test]
+    fn times_persist_across_remount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let clock = FakeClock::new();
+        let options = MountOptions::new().with_clock(clock.clone());
+        let mut fs = SFS::format_and_mount(dev, &FormatOptions::new(), &options).unwrap();
+        let fd = fs.open("/foo", OpenMode::CREATE | OpenMode::WO).unwrap();
+        let written = clock.advance(Duration::new(3, 250));
+        fs.write(fd, b"hello").unwrap();
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(fs.stat("/foo").unwrap().modified(), written);
     }
 }
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use crate::fs::OpenMode;
+
+/// A handle to an open file returned by `SFS::open`. Descriptors are only meaningful to the file
+/// system instance that handed them out.
+pub type FileDescriptor = u32;
+
+/// The state kept for each successful call to open, a single file may be opened many times with
+/// each descriptor tracking its own position.
+#[derive(Debug, Clone, Copy)]
+pub struct OpenFile {
+    /// The inode the descriptor is bound to.
+    pub inum: u32,
+    /// The options the file was opened with.
+    pub mode: OpenMode,
+    /// The byte offset the next read or write will start from.
+    pub cursor: u64,
+}
+
+impl OpenFile {
+    pub fn new(inum: u32, mode: OpenMode) -> Self {
+        Self {
+            inum,
+            mode,
+            cursor: 0,
+        }
+    }
+}
+
+/// The open file table of a file system. Like POSIX, new descriptors always take the lowest
+/// number not currently in use.
+pub struct HandleTable {
+    files: BTreeMap<FileDescriptor, OpenFile>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an open file to the table returning the descriptor it is reachable by.
+    pub fn insert(&mut self, file: OpenFile) -> FileDescriptor {
+        let mut fd = 0;
+        for &used in self.files.keys() {
+            if used != fd {
+                break;
+            }
+            fd += 1;
+        }
+
+        self.files.insert(fd, file);
+        fd
+    }
+
+    pub fn get(&self, fd: FileDescriptor) -> Option<&OpenFile> {
+        self.files.get(&fd)
+    }
+
+    pub fn get_mut(&mut self, fd: FileDescriptor) -> Option<&mut OpenFile> {
+        self.files.get_mut(&fd)
+    }
+
+    /// Whether any descriptor in the table refers to the inode.
+    pub fn is_open(&self, inum: u32) -> bool {
+        self.files.values().any(|file| file.inum == inum)
+    }
+
+    /// Iterates over the inodes held open by the table, once per descriptor.
+    pub fn inums<'a>(&'a self) -> impl Iterator<Item = u32> + 'a {
+        self.files.values().map(|file| file.inum)
+    }
+
+    /// Removes the descriptor from the table, making its number available for reuse.
+    pub fn remove(&mut self, fd: FileDescriptor) -> Option<OpenFile> {
+        self.files.remove(&fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors_are_allocated_sequentially() {
+        let mut table = HandleTable::new();
+
+        assert_eq!(table.insert(OpenFile::new(5, OpenMode::RO)), 0);
+        assert_eq!(table.insert(OpenFile::new(5, OpenMode::RO)), 1);
+        assert_eq!(table.insert(OpenFile::new(6, OpenMode::RO)), 2);
+        assert_eq!(table.get(2).unwrap().inum, 6);
+    }
+
+    #[test]
+    fn closed_descriptors_are_reused_lowest_first() {
+        let mut table = HandleTable::new();
+        for _ in 0..4 {
+            table.insert(OpenFile::new(1, OpenMode::RO));
+        }
+
+        table.remove(2).unwrap();
+        table.remove(1).unwrap();
+
+        assert_eq!(table.insert(OpenFile::new(1, OpenMode::RO)), 1);
+        assert_eq!(table.insert(OpenFile::new(1, OpenMode::RO)), 2);
+        assert_eq!(table.insert(OpenFile::new(1, OpenMode::RO)), 4);
+    }
+
+    #[test]
+    fn inode_is_open_until_last_descriptor_removed() {
+        let mut table = HandleTable::new();
+        let first = table.insert(OpenFile::new(7, OpenMode::RO));
+        let second = table.insert(OpenFile::new(7, OpenMode::RO));
+
+        table.remove(first);
+        assert!(table.is_open(7));
+        table.remove(second);
+        assert!(!table.is_open(7));
+    }
+}
@@ -0,0 +1,326 @@
+use crate::dir::{self, DirEntry};
+use crate::fs::{SFSError, BLOCK_SIZE};
+use crate::metadata::FileType;
+
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The hash function names are placed with, recorded in the root of every index.
+const HASH_VERSION: u8 = 1;
+/// The index held in the root block follows its "." and ".." records.
+const ROOT_INDEX_OFFSET: usize = 24;
+/// The index held in an interior node follows an unused record spanning the block.
+const NODE_INDEX_OFFSET: usize = 8;
+const HEADER_SIZE: usize = size_of::<IndexHeader>();
+const ENTRY_SIZE: usize = size_of::<IndexEntry>();
+
+/// The number of index entries that fit in the root block of a directory.
+pub const ROOT_LIMIT: usize = (BLOCK_SIZE - ROOT_INDEX_OFFSET - HEADER_SIZE) / ENTRY_SIZE;
+/// The number of index entries that fit in an interior node.
+pub const NODE_LIMIT: usize = (BLOCK_SIZE - NODE_INDEX_OFFSET - HEADER_SIZE) / ENTRY_SIZE;
+/// The most levels of interior nodes between the root and the leaves.
+pub const MAX_LEVELS: u8 = 1;
+
+#[repr(C)]
+#[derive(AsBytes, FromBytes, Debug, Default, Clone, Copy)]
+struct IndexHeader {
+    hash_version: u8,
+    /// The levels of interior nodes below the root, only meaningful in the root.
+    levels: u8,
+    count: u16,
+    reserved: u32,
+}
+
+/// Routes the names hashing to `hash` or above, up to the hash of the next entry, to a block of
+/// the directory.
+#[repr(C)]
+#[derive(AsBytes, FromBytes, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub hash: u32,
+    /// The logical block of the directory holding the next level down.
+    pub block: u32,
+}
+
+/// The root of a hashed directory index, kept in the first block of the directory.
+///
+/// An indexed directory is made up of the root, at most `MAX_LEVELS` levels of interior nodes and
+/// leaves. Leaves are ordinary directory blocks holding the entries whose names hash into the
+/// range routed to them. The root keeps the directory's "." and ".." records in front of the
+/// index and interior nodes hide theirs behind an unused record, so every block of the directory
+/// can still be listed by `dir::parse_block`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexRoot {
+    /// The inode linked as ".".
+    pub dir: u32,
+    /// The inode linked as "..".
+    pub parent: u32,
+    pub levels: u8,
+    /// The entries of the index sorted by hash, the first covering hash zero.
+    pub entries: Vec<IndexEntry>,
+}
+
+impl IndexRoot {
+    pub fn parse(block: &[u8]) -> Result<Self, SFSError> {
+        let dots = dir::parse_block(block)?;
+        let (dir, parent) = match dots.as_slice() {
+            [dot, dot_dot] if dot.name == "." && dot_dot.name == ".." => (dot.inum, dot_dot.inum),
+            _ => return Err(SFSError::CorruptDirectory),
+        };
+
+        let (header, entries) = parse_index(block, ROOT_INDEX_OFFSET, ROOT_LIMIT)?;
+        if header.levels > MAX_LEVELS || entries[0].hash != 0 {
+            return Err(SFSError::CorruptDirectory);
+        }
+        Ok(Self {
+            dir,
+            parent,
+            levels: header.levels,
+            entries,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let dot = |name: &str, inum| DirEntry {
+            name: OsString::from(name),
+            inum,
+            file_type: FileType::Directory,
+        };
+        let mut block = dir::serialize_entries(&[dot(".", self.dir), dot("..", self.parent)]);
+        serialize_index(&mut block, ROOT_INDEX_OFFSET, self.levels, &self.entries);
+        block
+    }
+}
+
+/// Decodes the entries of an interior node.
+pub fn parse_node(block: &[u8]) -> Result<Vec<IndexEntry>, SFSError> {
+    if !dir::parse_block(block)?.is_empty() {
+        return Err(SFSError::CorruptDirectory);
+    }
+    let (_, entries) = parse_index(block, NODE_INDEX_OFFSET, NODE_LIMIT)?;
+    Ok(entries)
+}
+
+/// Encodes at most `NODE_LIMIT` entries as an interior node.
+pub fn serialize_node(entries: &[IndexEntry]) -> Vec<u8> {
+    let mut block = dir::serialize_entries(&[]);
+    serialize_index(&mut block, NODE_INDEX_OFFSET, 0, entries);
+    block
+}
+
+fn parse_index(
+    block: &[u8],
+    offset: usize,
+    limit: usize,
+) -> Result<(IndexHeader, Vec<IndexEntry>), SFSError> {
+    let mut header = IndexHeader::default();
+    header
+        .as_bytes_mut()
+        .copy_from_slice(&block[offset..offset + HEADER_SIZE]);
+    let count = header.count as usize;
+    if header.hash_version != HASH_VERSION || count == 0 || count > limit {
+        return Err(SFSError::CorruptDirectory);
+    }
+
+    let mut entries = vec![IndexEntry::default(); count];
+    let start = offset + HEADER_SIZE;
+    entries
+        .as_bytes_mut()
+        .copy_from_slice(&block[start..start + count * ENTRY_SIZE]);
+    if entries.windows(2).any(|pair| pair[0].hash >= pair[1].hash) {
+        return Err(SFSError::CorruptDirectory);
+    }
+    Ok((header, entries))
+}
+
+fn serialize_index(block: &mut [u8], offset: usize, levels: u8, entries: &[IndexEntry]) {
+    let header = IndexHeader {
+        hash_version: HASH_VERSION,
+        levels,
+        count: entries.len() as u16,
+        reserved: 0,
+    };
+    block[offset..offset + HEADER_SIZE].copy_from_slice(header.as_bytes());
+    let start = offset + HEADER_SIZE;
+    block[start..start + entries.len() * ENTRY_SIZE].copy_from_slice(entries.as_bytes());
+}
+
+/// The path taken through a directory index to reach the leaf covering a hash.
+pub struct IndexPath {
+    pub root: IndexRoot,
+    /// The position of the root entry followed.
+    pub root_pos: usize,
+    /// The block of the interior node followed, its entries and the position of the entry
+    /// followed, if the index has a level of them.
+    pub node: Option<(u32, Vec<IndexEntry>, usize)>,
+    pub leaf: u32,
+}
+
+/// Returns the position of the entry routing `hash`, the last one starting at or below it.
+pub fn find(entries: &[IndexEntry], hash: u32) -> usize {
+    // Entries never compare equal so the search ends past the last entry at or below the hash.
+    entries
+        .binary_search_by(|entry| {
+            if entry.hash <= hash {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        })
+        .unwrap_or_else(|pos| pos)
+        .saturating_sub(1)
+}
+
+/// Hashes a name to place it in the index (32-bit FNV-1a). Changing it changes the on-disk
+/// format, see `HASH_VERSION`.
+pub fn hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Splits the entries of an overflowing leaf in two by hash, keeping names with the same hash
+/// together. Returns the lower half, the hash starting the upper half and the upper half, or
+/// `None` if no split leaves both halves fitting in a block.
+pub fn split_leaf(mut entries: Vec<DirEntry>) -> Option<(Vec<DirEntry>, u32, Vec<DirEntry>)> {
+    let hash_of = |entry: &DirEntry| hash(entry.name.as_bytes());
+    entries.sort_by_key(hash_of);
+
+    // Split close to the middle by size, then move to the nearest change of hash.
+    let total: usize = entries.iter().map(dir::entry_len).sum();
+    let mut size = 0;
+    let middle = entries
+        .iter()
+        .position(|entry| {
+            size += dir::entry_len(entry);
+            size > total / 2
+        })
+        .unwrap_or(0)
+        .max(1);
+    let boundary = |at: &usize| hash_of(&entries[at - 1]) != hash_of(&entries[*at]);
+    let split = (middle..entries.len())
+        .find(boundary)
+        .or_else(|| (1..middle).rev().find(boundary))?;
+
+    let upper = entries.split_off(split);
+    if !dir::fits_block(&entries) || !dir::fits_block(&upper) {
+        return None;
+    }
+    let split_hash = hash_of(&upper[0]);
+    Some((entries, split_hash, upper))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, inum: u32) -> DirEntry {
+        DirEntry {
+            name: OsString::from(name),
+            inum,
+            file_type: FileType::RegularFile,
+        }
+    }
+
+    fn index_entries(hashes: &[u32]) -> Vec<IndexEntry> {
+        hashes
+            .iter()
+            .enumerate()
+            .map(|(i, &hash)| IndexEntry {
+                hash,
+                block: i as u32 + 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn root_round_trips_and_lists_as_dot_entries() {
+        let root = IndexRoot {
+            dir: 7,
+            parent: 3,
+            levels: 1,
+            entries: index_entries(&[0, 100, 2000]),
+        };
+
+        let block = root.serialize();
+
+        assert_eq!(block.len(), BLOCK_SIZE);
+        assert_eq!(IndexRoot::parse(&block).unwrap(), root);
+        let names: Vec<OsString> = dir::parse_block(&block)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, vec![".", ".."]);
+    }
+
+    #[test]
+    fn nodes_round_trip_and_list_as_empty() {
+        let entries = index_entries(&(0..NODE_LIMIT as u32).collect::<Vec<u32>>());
+
+        let block = serialize_node(&entries);
+
+        assert_eq!(parse_node(&block).unwrap(), entries);
+        assert!(dir::parse_block(&block).unwrap().is_empty());
+        assert_eq!(ROOT_LIMIT, 508);
+        assert_eq!(NODE_LIMIT, 510);
+    }
+
+    #[test]
+    fn unsorted_or_empty_index_is_corrupt() {
+        let mut root = IndexRoot {
+            dir: 1,
+            parent: 0,
+            levels: 0,
+            entries: index_entries(&[0, 50, 50]),
+        };
+        assert!(IndexRoot::parse(&root.serialize()).is_err());
+
+        root.entries.clear();
+        assert!(IndexRoot::parse(&root.serialize()).is_err());
+        assert!(parse_node(&serialize_node(&[])).is_err());
+        assert!(parse_node(&dir::serialize_entries(&[entry("foo", 1)])).is_err());
+    }
+
+    #[test]
+    fn find_follows_last_entry_at_or_below_hash() {
+        let entries = index_entries(&[0, 100, 2000]);
+
+        assert_eq!(find(&entries, 0), 0);
+        assert_eq!(find(&entries, 99), 0);
+        assert_eq!(find(&entries, 100), 1);
+        assert_eq!(find(&entries, std::u32::MAX), 2);
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        assert_eq!(hash(b""), 0x811c_9dc5);
+        assert_eq!(hash(b"a"), 0xe40c_292c);
+        assert_eq!(hash(b"foobar"), 0xbf9c_f968);
+    }
+
+    #[test]
+    fn split_leaf_divides_by_hash() {
+        let entries: Vec<DirEntry> = (0..300).map(|i| entry(&format!("file{}", i), i)).collect();
+
+        let (lower, split_hash, upper) = split_leaf(entries).unwrap();
+
+        assert_eq!(lower.len() + upper.len(), 300);
+        assert!(lower
+            .iter()
+            .all(|entry| hash(entry.name.as_bytes()) < split_hash));
+        assert!(upper
+            .iter()
+            .all(|entry| hash(entry.name.as_bytes()) >= split_hash));
+        assert!(dir::fits_block(&lower) && dir::fits_block(&upper));
+    }
+
+    #[test]
+    fn split_leaf_keeps_equal_hashes_together() {
+        let entries = vec![entry("same", 1), entry("same", 2), entry("same", 3)];
+
+        assert!(split_leaf(entries).is_none());
+    }
+}
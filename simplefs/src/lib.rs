@@ -2,9 +2,25 @@
 extern crate log;
 
 mod alloc;
+mod clock;
+mod cred;
+mod dir;
+mod extent;
+mod format;
 mod fs;
+mod handle;
+mod htree;
 pub mod io;
+mod metadata;
+mod mount;
 mod node;
 mod sb;
 
-pub use fs::SFS;
+pub use clock::{Clock, SystemClock, TimeUpdate};
+pub use cred::Credentials;
+pub use dir::{DirEntry, ReadDir};
+pub use format::FormatOptions;
+pub use fs::{OpenMode, SFSError, SFS};
+pub use handle::FileDescriptor;
+pub use metadata::{FileType, Metadata, Permissions};
+pub use mount::{AtimePolicy, MountOptions};
@@ -0,0 +1,155 @@
+use crate::node::Inode;
+
+use std::time::SystemTime;
+
+/// The kind of object an inode holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    RegularFile,
+    Directory,
+    Symlink,
+}
+
+impl FileType {
+    pub fn is_dir(self) -> bool {
+        self == FileType::Directory
+    }
+
+    pub fn is_file(self) -> bool {
+        self == FileType::RegularFile
+    }
+
+    pub fn is_symlink(self) -> bool {
+        self == FileType::Symlink
+    }
+}
+
+/// The permission bits of a file, the lower 12 bits of its mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    mode: u16,
+}
+
+impl Permissions {
+    /// Returns the permission bits e.g. `0o755`, including the setuid, setgid and sticky bits.
+    pub fn mode(self) -> u32 {
+        u32::from(self.mode & 0o7777)
+    }
+
+    /// Whether nobody has permission to write to the file.
+    pub fn readonly(self) -> bool {
+        self.mode & 0o222 == 0
+    }
+}
+
+/// Information about a file returned by `SFS::stat` and `SFS::fstat`. Modeled after
+/// `std::fs::Metadata` along with the unix extensions to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    ino: u32,
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    nlink: u16,
+    size: u64,
+    blocks: u64,
+    created: SystemTime,
+    modified: SystemTime,
+    accessed: SystemTime,
+    changed: SystemTime,
+    file_type: FileType,
+}
+
+impl Metadata {
+    pub(crate) fn new(inum: u32, node: &Inode) -> Self {
+        Self {
+            ino: inum,
+            mode: node.mode,
+            uid: node.uid,
+            gid: node.gid,
+            nlink: node.links_count,
+            size: node.size(),
+            blocks: u64::from(node.block_count),
+            created: node.created().to_system_time(),
+            modified: node.modified().to_system_time(),
+            accessed: node.accessed().to_system_time(),
+            changed: node.changed().to_system_time(),
+            file_type: node.file_type(),
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type.is_symlink()
+    }
+
+    /// The size of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        Permissions { mode: self.mode }
+    }
+
+    /// The inode number of the file.
+    pub fn ino(&self) -> u32 {
+        self.ino
+    }
+
+    /// The full mode of the file, both the file type and permission bits.
+    pub fn mode(&self) -> u32 {
+        u32::from(self.mode)
+    }
+
+    pub fn uid(&self) -> u32 {
+        u32::from(self.uid)
+    }
+
+    pub fn gid(&self) -> u32 {
+        u32::from(self.gid)
+    }
+
+    /// The number of hard links to the file.
+    pub fn nlink(&self) -> u64 {
+        u64::from(self.nlink)
+    }
+
+    /// The number of file system blocks allocated to the file. Unlike `std`, this counts whole file
+    /// system blocks rather than 512 byte units.
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    pub fn accessed(&self) -> SystemTime {
+        self.accessed
+    }
+
+    /// The last time the file's inode changed, like `st_ctime`.
+    pub fn changed(&self) -> SystemTime {
+        self.changed
+    }
+}
@@ -0,0 +1,69 @@
+use crate::clock::{Clock, SystemClock};
+use crate::cred::Credentials;
+
+use std::sync::Arc;
+
+/// When reading a file updates its access time, in the spirit of the `strictatime`, `relatime`
+/// and `noatime` mount options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtimePolicy {
+    /// Every read updates the access time.
+    Strict,
+    /// Reads only update the access time if it is older than the modification or change time, or
+    /// more than a day old. This keeps "has it been read since it changed" answerable while
+    /// sparing most reads an inode write.
+    Relatime,
+    /// Reads never update the access time.
+    NoAtime,
+}
+
+/// Options for a mounted file system, see `SFS::mount`.
+///
+/// The defaults read the time from the operating system, update access times following
+/// `AtimePolicy::Relatime` and make calls with the credentials of root.
+///
+/// ```
+/// use simplefs::{AtimePolicy, MountOptions};
+///
+/// let options = MountOptions::new().with_atime(AtimePolicy::NoAtime);
+/// ```
+#[derive(Clone)]
+pub struct MountOptions {
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) atime: AtimePolicy,
+    pub(crate) creds: Credentials,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            atime: AtimePolicy::Relatime,
+            creds: Credentials::default(),
+        }
+    }
+}
+
+impl MountOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the clock file times are stamped from.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets when reads update access times.
+    pub fn with_atime(mut self, atime: AtimePolicy) -> Self {
+        self.atime = atime;
+        self
+    }
+
+    /// Sets the credentials calls are checked against, see `SFS::set_credentials`.
+    pub fn with_credentials(mut self, creds: Credentials) -> Self {
+        self.creds = creds;
+        self
+    }
+}
@@ -1,56 +1,113 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::alloc::{Bitmap, NextAvailableAllocation, State};
+use crate::metadata::FileType;
 
 use zerocopy::{AsBytes, FromBytes};
 
 const BLOCK_SIZE: u32 = 4096;
 const NODE_SIZE: u32 = 256;
 const NODES_PER_BLOCK: u32 = BLOCK_SIZE / NODE_SIZE;
-const ROOT_DEFAULT_MODE: u16 = 0x4000;
-const DEFAULT_MODE: u16 = 0x2000;
+const ROOT_DEFAULT_MODE: u16 = DIR_TYPE | 0o755;
+const DEFAULT_MODE: u16 = FILE_TYPE | 0o644;
+const DIR_DEFAULT_MODE: u16 = DIR_TYPE | 0o755;
+const SYMLINK_DEFAULT_MODE: u16 = SYMLINK_TYPE | 0o777;
+/// The high bits of the mode encode the type of file, the low bits its permissions.
+const FILE_TYPE_MASK: u16 = 0xF000;
+const DIR_TYPE: u16 = 0x4000;
+const FILE_TYPE: u16 = 0x8000;
+const SYMLINK_TYPE: u16 = 0xA000;
+/// Executing the file runs it as its owner.
+pub const SETUID: u16 = 0o4000;
+/// Executing the file runs it with its group. Files created in a directory with the bit set belong
+/// to the directory's group and new directories inherit the bit.
+pub const SETGID: u16 = 0o2000;
+/// Entries of a directory with the bit set can only be removed by their owner or the directory's.
+pub const STICKY: u16 = 0o1000;
+/// The number of block pointers held directly in an inode.
+pub const DIRECT_BLOCKS: usize = 15;
+/// The number of block pointers that fit in an indirect block.
+pub const POINTERS_PER_BLOCK: usize = BLOCK_SIZE as usize / 4;
+/// The inode maps its data with extents held in `Inode::blocks` rather than block pointers.
+pub const EXTENTS_FLAG: u32 = 0x1;
+/// The inode's extents outgrew it, `Inode::blocks` instead indexes the blocks holding them.
+pub const EXTENT_INDEX_FLAG: u32 = 0x2;
+/// The directory locates its entries through a hashed index, see `htree::IndexRoot`.
+pub const DIR_INDEX_FLAG: u32 = 0x4;
+/// The inode's data is held in `Inode::inline_data` rather than in data blocks.
+pub const INLINE_DATA_FLAG: u32 = 0x8;
+/// The number of bytes of data that can be kept inline in an inode.
+pub const INLINE_DATA_LEN: usize = 64;
+/// A block pointer mapping no block, the part of the file it covers is a hole and reads as zeros.
+/// Block 0 always holds the superblock so it never belongs to a file.
+pub const HOLE: u32 = 0;
 
 #[repr(C)]
 #[derive(AsBytes, FromBytes, Copy, Clone)]
 /// This structure __must not exceed 256 bytes.__
 pub struct Inode {
     /// The file mode (e.g full access - drwxrwxrwx).
-    mode: u16,
+    pub mode: u16,
     /// The id of the owning user.
-    uid: u16,
+    pub uid: u16,
     /// The id of the owning group.
-    gid: u16,
+    pub gid: u16,
     /// The number of links to this file.
-    links_count: u16,
-    /// The total size of the file in bytes.
-    size: u32,
-    /// The time the file was created in milliseconds since epoch.
-    create_time: u32,
-    /// The time the file was last updated in milliseconds since epoch.
-    update_time: u32,
-    /// The time the file was last accessed in milliseconds since epoch.
-    access_time: u32,
+    pub links_count: u16,
+    /// The low 32 bits of the size of the file in bytes, see `Inode::size`.
+    size_lo: u32,
+    /// Creation, modification and access times in milliseconds since epoch as recorded by images
+    /// formatted without `FEATURE_WIDE_INODES`. Only read when upgrading such an image.
+    legacy_times: [u32; 3],
+    /// The number of disk blocks allocated to the file, indirect blocks included.
+    pub block_count: u32,
+    /// Pointers to the single, double and triple indirect blocks of the file. Each indirect block
+    /// holds pointers to the next level of indirect blocks, or for the last level to data blocks.
+    pub indirect: [u32; 3],
+    /// Describes how the inode is stored, see the `*_FLAG` constants.
+    pub flags: u32,
+    /// The high 32 bits of the size of the file in bytes.
+    size_hi: u32,
+    /// Seconds since epoch of the last access, data modification, inode change and creation.
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    crtime: u64,
+    /// The nanoseconds past the second of each of the timestamps above.
+    atime_nsec: u32,
+    mtime_nsec: u32,
+    ctime_nsec: u32,
+    crtime_nsec: u32,
+    /// Data small enough to live in the inode itself, such as the target of a short symbolic link.
+    inline_data: [u8; INLINE_DATA_LEN],
     /// Reserved for future expansion of file attributes up to 256 byte limit.
-    // TODO(allancalix): Fill in the rest of the metadata like  symlink information etc.
-    padding: [u32; 43],
-    /// Pointers for the data blocks that belong to the file. Uses the remaining
-    /// space the 256 inode space.
-    pub blocks: [u32; 15],
+    padding: [u32; 9],
+    /// Pointers for the first data blocks that belong to the file, `HOLE` where it has none. Uses
+    /// the remaining space the 256 inode space.
+    pub blocks: [u32; DIRECT_BLOCKS],
 }
 
 impl Inode {
     fn root() -> Self {
         Self {
             mode: ROOT_DEFAULT_MODE,
-            uid: 0,
-            gid: 0,
-            links_count: 0,
-            size: 0,
-            create_time: 0,
-            update_time: 0,
-            access_time: 0,
-            padding: [0; 43],
-            blocks: [0; 15],
+            ..Inode::dir()
+        }
+    }
+
+    fn dir() -> Self {
+        Self {
+            mode: DIR_DEFAULT_MODE,
+            links_count: 2,
+            ..Inode::default()
+        }
+    }
+
+    fn symlink() -> Self {
+        Self {
+            mode: SYMLINK_DEFAULT_MODE,
+            ..Inode::default()
         }
     }
 
@@ -59,42 +116,227 @@ impl Inode {
             mode: DEFAULT_MODE,
             uid: 0,
             gid: 0,
-            links_count: 0,
-            size: 0,
-            create_time: 0,
-            update_time: 0,
-            access_time: 0,
-            padding: [0; 43],
-            blocks: [0; 15],
+            links_count: 1,
+            size_lo: 0,
+            legacy_times: [0; 3],
+            block_count: 0,
+            indirect: [0; 3],
+            flags: 0,
+            size_hi: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            crtime: 0,
+            atime_nsec: 0,
+            mtime_nsec: 0,
+            ctime_nsec: 0,
+            crtime_nsec: 0,
+            inline_data: [0; INLINE_DATA_LEN],
+            padding: [0; 9],
+            blocks: [0; DIRECT_BLOCKS],
+        }
+    }
+
+    /// Whether the inode holds a directory rather than file data.
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == FileType::Directory
+    }
+
+    /// The size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        u64::from(self.size_hi) << 32 | u64::from(self.size_lo)
+    }
+
+    /// Replaces the permission bits of the mode, those below the file type, with `bits`.
+    pub fn set_permissions(&mut self, bits: u16) {
+        self.mode = self.mode & FILE_TYPE_MASK | bits & !FILE_TYPE_MASK;
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.size_lo = size as u32;
+        self.size_hi = (size >> 32) as u32;
+    }
+
+    /// The time the file was last read.
+    pub fn accessed(&self) -> Timestamp {
+        Timestamp::new(self.atime, self.atime_nsec)
+    }
+
+    /// The time the file's contents last changed.
+    pub fn modified(&self) -> Timestamp {
+        Timestamp::new(self.mtime, self.mtime_nsec)
+    }
+
+    /// The time the inode itself last changed, e.g. its contents, links or permissions.
+    pub fn changed(&self) -> Timestamp {
+        Timestamp::new(self.ctime, self.ctime_nsec)
+    }
+
+    pub fn created(&self) -> Timestamp {
+        Timestamp::new(self.crtime, self.crtime_nsec)
+    }
+
+    pub fn set_accessed(&mut self, time: Timestamp) {
+        self.atime = time.secs;
+        self.atime_nsec = time.nsecs;
+    }
+
+    pub fn set_modified(&mut self, time: Timestamp) {
+        self.mtime = time.secs;
+        self.mtime_nsec = time.nsecs;
+    }
+
+    pub fn set_changed(&mut self, time: Timestamp) {
+        self.ctime = time.secs;
+        self.ctime_nsec = time.nsecs;
+    }
+
+    pub fn set_created(&mut self, time: Timestamp) {
+        self.crtime = time.secs;
+        self.crtime_nsec = time.nsecs;
+    }
+
+    /// Moves the millisecond timestamps of an inode written without `FEATURE_WIDE_INODES` into
+    /// their wide fields. The change time was not recorded so it takes the modification time.
+    fn upgrade_legacy(&mut self) {
+        let [created, modified, accessed] = self.legacy_times;
+        self.set_created(Timestamp::from_millis(created));
+        self.set_modified(Timestamp::from_millis(modified));
+        self.set_changed(Timestamp::from_millis(modified));
+        self.set_accessed(Timestamp::from_millis(accessed));
+        self.legacy_times = [0; 3];
+    }
+
+    /// Whether the inode maps its data with extents.
+    pub fn uses_extents(&self) -> bool {
+        self.flags & EXTENTS_FLAG != 0
+    }
+
+    /// The data kept inline in the inode, see `INLINE_DATA_FLAG`.
+    pub fn inline_data(&self) -> &[u8] {
+        let len = (self.size() as usize).min(INLINE_DATA_LEN);
+        &self.inline_data[..len]
+    }
+
+    /// Keeps `data`, at most `INLINE_DATA_LEN` bytes, inline in the inode as its contents.
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        self.inline_data = [0; INLINE_DATA_LEN];
+        self.inline_data[..data.len()].copy_from_slice(data);
+        self.flags |= INLINE_DATA_FLAG;
+        self.set_size(data.len() as u64);
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self.mode & FILE_TYPE_MASK {
+            DIR_TYPE => FileType::Directory,
+            SYMLINK_TYPE => FileType::Symlink,
+            _ => FileType::RegularFile,
         }
     }
 
     fn parse(buf: &[u8]) -> Self {
+        assert!(buf.len() >= NODE_SIZE as usize);
         let inode = buf.as_ptr() as *const Inode;
-        unsafe { *inode }
+        // Nodes are read out of arbitrary offsets of a byte buffer.
+        unsafe { std::ptr::read_unaligned(inode) }
+    }
+}
+
+/// Locates the pointer to a logical block of a file within the inode's tree of block pointers.
+#[derive(Debug, PartialEq)]
+pub enum BlockPath {
+    /// The pointer is held in `Inode::blocks` at the index.
+    Direct(usize),
+    /// The pointer is reached from `Inode::indirect[depth - 1]` through `depth` levels of indirect
+    /// blocks, following the pointer at `offsets[level]` in each.
+    Indirect { depth: usize, offsets: [usize; 3] },
+}
+
+impl BlockPath {
+    /// Returns the path to the `index`th block of a file, or `None` if the index is beyond what the
+    /// triple indirect block can address.
+    pub fn new(index: u64) -> Option<Self> {
+        if index < DIRECT_BLOCKS as u64 {
+            return Some(BlockPath::Direct(index as usize));
+        }
+
+        let per_block = POINTERS_PER_BLOCK as u64;
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let mut span = per_block;
+        for depth in 1..=3 {
+            if index < span {
+                let mut offsets = [0; 3];
+                for level in (0..depth).rev() {
+                    offsets[level] = (index % per_block) as usize;
+                    index /= per_block;
+                }
+                return Some(BlockPath::Indirect { depth, offsets });
+            }
+            index -= span;
+            span *= per_block;
+        }
+        None
+    }
+}
+
+/// A point in time as seconds and nanoseconds since the unix epoch.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub secs: u64,
+    pub nsecs: u32,
+}
+
+impl Timestamp {
+    pub fn new(secs: u64, nsecs: u32) -> Self {
+        Self { secs, nsecs }
+    }
+
+    fn from_millis(millis: u32) -> Self {
+        Self::new(u64::from(millis / 1000), millis % 1000 * 1_000_000)
+    }
+
+    /// Converts a system time, returning `None` for times before the unix epoch.
+    pub fn from_system_time(time: SystemTime) -> Option<Self> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self::new(since_epoch.as_secs(), since_epoch.subsec_nanos()))
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.secs, self.nsecs)
     }
 }
 
 pub struct InodeGroup {
     nodes: BTreeMap<u32, Inode>,
     alloc_tracker: Bitmap,
+    /// The number of inodes the on-disk inode table has room for.
+    capacity: u32,
+    /// The flags every new inode starts out with.
+    node_flags: u32,
+    /// Disk blocks (relative to the start of the inode table) holding nodes changed since the
+    /// last flush.
+    dirty_blocks: BTreeSet<u32>,
 }
 
 impl InodeGroup {
-    pub fn new(alloc_tracker: Bitmap) -> Self {
-        let mut group = Self {
-            nodes: BTreeMap::new(),
-            alloc_tracker,
-        };
+    pub fn new(alloc_tracker: Bitmap, capacity: u32, node_flags: u32) -> Self {
+        let mut group = Self::open(alloc_tracker, capacity, node_flags);
 
-        group.insert(0, Inode::root());
+        let root = Inode {
+            flags: node_flags,
+            ..Inode::root()
+        };
+        group.insert(0, root);
         group
     }
 
-    pub fn open(alloc_tracker: Bitmap) -> Self {
+    pub fn open(alloc_tracker: Bitmap, capacity: u32, node_flags: u32) -> Self {
         Self {
             nodes: BTreeMap::new(),
             alloc_tracker,
+            capacity,
+            node_flags,
+            dirty_blocks: BTreeSet::new(),
         }
     }
 
@@ -102,8 +344,13 @@ impl InodeGroup {
         self.nodes.get(&inum)
     }
 
+    /// Returns a node for modification, the disk block holding it is assumed to have changed.
     pub fn get_mut(&mut self, inum: u32) -> Option<&mut Inode> {
-        self.nodes.get_mut(&inum)
+        let node = self.nodes.get_mut(&inum);
+        if node.is_some() {
+            self.dirty_blocks.insert(inum / NODES_PER_BLOCK);
+        }
+        node
     }
 
     pub fn allocations(&self) -> &Bitmap {
@@ -115,42 +362,97 @@ impl InodeGroup {
         self.nodes.len()
     }
 
+    /// Returns the inumbers of every directory in the table.
+    pub fn directories(&self) -> Vec<u32> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.is_dir())
+            .map(|(&inum, _)| inum)
+            .collect()
+    }
+
     /// Allocates a regular file Inode into the table and returns the new reserved node allocation
-    /// block index (i.e. the inumber). Panics if there is no space left to allocate another node.
-    pub fn new_file(&mut self) -> u32 {
-        // TODO(allancalix): The cap for this is hardcoded to support 5 blocks of inodes. Update when
-        // the 5 block restriction is lifted.
-        let mut alloc_gen =
-            NextAvailableAllocation::new(self.alloc_tracker, Some(NODES_PER_BLOCK as usize * 5));
-        let inum = alloc_gen.next();
-        if inum.is_none() {
-            panic!("No free space left to allocate nodes.")
-        }
+    /// block index (i.e. the inumber), or `None` if there is no space left to allocate another node.
+    pub fn new_file(&mut self) -> Option<u32> {
+        self.allocate(Inode::default())
+    }
+
+    /// Allocates a directory Inode into the table and returns its inumber. The directory starts
+    /// with two links, the entry in its parent and its own "." entry. Returns `None` if there is no
+    /// space left to allocate another node.
+    pub fn new_dir(&mut self) -> Option<u32> {
+        self.allocate(Inode::dir())
+    }
+
+    /// Allocates a symbolic link Inode into the table and returns its inumber. Returns `None` if
+    /// there is no space left to allocate another node.
+    pub fn new_symlink(&mut self) -> Option<u32> {
+        self.allocate(Inode::symlink())
+    }
+
+    /// Drops the node from the table and releases its inumber for reuse.
+    pub fn remove(&mut self, inum: u32) -> Option<Inode> {
+        self.alloc_tracker.set_free(inum as usize);
+        self.dirty_blocks.insert(inum / NODES_PER_BLOCK);
+        self.nodes.remove(&inum)
+    }
+
+    /// Returns the disk blocks (relative to the start of the inode table) changed since the last
+    /// call, clearing them.
+    pub fn take_dirty_blocks(&mut self) -> Vec<u32> {
+        let blocks = self.dirty_blocks.iter().copied().collect();
+        self.dirty_blocks.clear();
+        blocks
+    }
 
-        let inum = inum.unwrap() as u32;
-        let new_node = Inode::default();
-        self.insert(inum, new_node);
-        inum
+    /// Returns the blocks of the allocation tracker changed since the last call, clearing them.
+    pub fn take_dirty_allocations(&mut self) -> Vec<u32> {
+        self.alloc_tracker.take_dirty_blocks()
     }
+
+    fn allocate(&mut self, mut node: Inode) -> Option<u32> {
+        node.flags = self.node_flags;
+        let mut alloc_gen =
+            NextAvailableAllocation::new(&self.alloc_tracker, Some(self.capacity as usize));
+        let inum = alloc_gen.next()? as u32;
+        self.insert(inum, node);
+        Some(inum)
+    }
+
     /// Loads a disk block of inodes into the in-memory tree.
     pub fn load_block(&mut self, disk_block: u32, block_buf: &[u8]) {
         let block_start = disk_block * NODES_PER_BLOCK;
         let block_end = block_start + NODES_PER_BLOCK;
         for i in block_start..block_end {
             if let State::Used = self.alloc_tracker.get(i as usize) {
-                let node_offset = block_start as usize % NODE_SIZE as usize;
-                let node = Inode::parse(&block_buf[node_offset..NODE_SIZE as usize]);
+                let node_offset = ((i - block_start) * NODE_SIZE) as usize;
+                let mut node =
+                    Inode::parse(&block_buf[node_offset..node_offset + NODE_SIZE as usize]);
+                if node.block_count == 0 {
+                    // Nodes written before the count was kept only ever used direct blocks.
+                    node.block_count =
+                        node.blocks.iter().filter(|&&block| block != 0).count() as u32;
+                }
                 self.nodes.insert(i, node);
             }
         }
     }
 
+    /// Converts every loaded node of an image formatted without `FEATURE_WIDE_INODES` to the wide
+    /// layout, marking them all for writing back.
+    pub fn upgrade_legacy_nodes(&mut self) {
+        for (inum, node) in self.nodes.iter_mut() {
+            node.upgrade_legacy();
+            self.dirty_blocks.insert(inum / NODES_PER_BLOCK);
+        }
+    }
+
     /// Serializes an entire disk block of inodes for writing to disk.
     pub fn serialize_block(&self, disk_block: u32) -> Vec<u8> {
         let mut block_buf = vec![0; 4096];
         let offset = disk_block * NODES_PER_BLOCK;
-        for (i, node) in self.nodes.range(offset..NODES_PER_BLOCK) {
-            let node_offset = *i as usize * NODE_SIZE as usize;
+        for (i, node) in self.nodes.range(offset..offset + NODES_PER_BLOCK) {
+            let node_offset = ((*i - offset) * NODE_SIZE) as usize;
             block_buf[node_offset..node_offset + NODE_SIZE as usize]
                 .copy_from_slice(node.as_bytes());
         }
@@ -159,10 +461,11 @@ impl InodeGroup {
     }
 
     fn insert(&mut self, node_block: u32, node: Inode) -> usize {
-        // TODO(allancalix): Allocation tracker needs write to disk on insert.
         self.alloc_tracker.set_reserved(node_block as usize);
         self.nodes.insert(node_block, node);
-        self.get_disk_block(node_block)
+        let disk_block = self.get_disk_block(node_block);
+        self.dirty_blocks.insert(disk_block as u32);
+        disk_block
     }
 
     fn get_disk_block(&self, node_block: u32) -> usize {
@@ -188,10 +491,101 @@ mod tests {
         assert_eq!(root.gid, parsed_root.gid);
     }
 
+    #[test]
+    fn inode_fills_its_slot_exactly() {
+        assert_eq!(std::mem::size_of::<Inode>(), NODE_SIZE as usize);
+    }
+
+    #[test]
+    fn sizes_beyond_four_gigabytes_round_trip() {
+        let mut node = Inode::default();
+        node.set_size(5 << 30 | 7);
+
+        let parsed = Inode::parse(node.as_bytes());
+
+        assert_eq!(parsed.size(), 5 << 30 | 7);
+        assert_eq!(parsed.size_lo, 7 | (1 << 30));
+        assert_eq!(parsed.size_hi, 1);
+    }
+
+    #[test]
+    fn legacy_millisecond_times_are_upgraded() {
+        let mut group = InodeGroup::new(Bitmap::new(1), 80, 0);
+        let inum = group.new_file().unwrap();
+        group.get_mut(inum).unwrap().legacy_times = [1_500, 2_250, 3_001];
+        group.take_dirty_blocks();
+
+        group.upgrade_legacy_nodes();
+
+        let node = group.get(inum).unwrap();
+        assert_eq!(node.created(), Timestamp::new(1, 500_000_000));
+        assert_eq!(node.modified(), Timestamp::new(2, 250_000_000));
+        assert_eq!(node.changed(), Timestamp::new(2, 250_000_000));
+        assert_eq!(node.accessed(), Timestamp::new(3, 1_000_000));
+        assert_eq!(node.legacy_times, [0; 3]);
+        assert_eq!(group.take_dirty_blocks(), vec![0]);
+    }
+
+    #[test]
+    fn block_paths_cover_each_level_of_indirection() {
+        let per_block = POINTERS_PER_BLOCK as u64;
+        let single = DIRECT_BLOCKS as u64;
+        let double = single + per_block;
+        let triple = double + per_block * per_block;
+
+        assert_eq!(BlockPath::new(0), Some(BlockPath::Direct(0)));
+        assert_eq!(BlockPath::new(single - 1), Some(BlockPath::Direct(14)));
+        assert_eq!(
+            BlockPath::new(single),
+            Some(BlockPath::Indirect {
+                depth: 1,
+                offsets: [0, 0, 0]
+            })
+        );
+        assert_eq!(
+            BlockPath::new(double - 1),
+            Some(BlockPath::Indirect {
+                depth: 1,
+                offsets: [1023, 0, 0]
+            })
+        );
+        assert_eq!(
+            BlockPath::new(double + per_block + 2),
+            Some(BlockPath::Indirect {
+                depth: 2,
+                offsets: [1, 2, 0]
+            })
+        );
+        assert_eq!(
+            BlockPath::new(triple + per_block * per_block + 3 * per_block + 4),
+            Some(BlockPath::Indirect {
+                depth: 3,
+                offsets: [1, 3, 4]
+            })
+        );
+        assert_eq!(
+            BlockPath::new(triple + per_block * per_block * per_block),
+            None
+        );
+    }
+
+    #[test]
+    fn loading_node_without_block_count_counts_direct_blocks() {
+        let mut group = InodeGroup::new(Bitmap::new(1), 80, 0);
+        let inum = group.new_file().unwrap();
+        group.get_mut(inum).unwrap().blocks[..3].copy_from_slice(&[9, 10, 12]);
+
+        let mut loaded = InodeGroup::open(group.allocations().clone(), 80, 0);
+        loaded.load_block(0, &group.serialize_block(0));
+
+        assert_eq!(loaded.get(inum).unwrap().block_count, 3);
+        assert_eq!(loaded.get(0).unwrap().block_count, 0);
+    }
+
     #[test]
     fn can_retrieve_inserted_inode() {
-        let nodes_map = Bitmap::new();
-        let mut group = InodeGroup::new(nodes_map);
+        let nodes_map = Bitmap::new(1);
+        let mut group = InodeGroup::new(nodes_map, 80, 0);
         let mut node = Inode::default();
         node.uid = 100;
         node.gid = 100;
@@ -200,4 +594,78 @@ mod tests {
         assert_eq!(group.get(1).unwrap().uid, 100);
         assert_eq!(group.get(1).unwrap().gid, 100);
     }
+
+    #[test]
+    fn can_serialize_and_load_blocks_of_inodes() {
+        let mut group = InodeGroup::new(Bitmap::new(1), 80, 0);
+        for _ in 0..NODES_PER_BLOCK + 2 {
+            let inum = group.new_file().unwrap();
+            group.get_mut(inum).unwrap().uid = inum as u16;
+        }
+
+        let mut loaded = InodeGroup::open(group.allocations().clone(), 80, 0);
+        for block in 0..2 {
+            loaded.load_block(block, &group.serialize_block(block));
+        }
+
+        assert_eq!(loaded.total_nodes(), group.total_nodes());
+        for inum in 1..NODES_PER_BLOCK + 3 {
+            assert_eq!(loaded.get(inum).unwrap().uid, inum as u16);
+        }
+        assert!(loaded.get(0).unwrap().is_dir());
+    }
+
+    #[test]
+    fn modified_nodes_mark_their_block_dirty() {
+        let mut group = InodeGroup::new(Bitmap::new(1), 80, 0);
+        assert_eq!(group.take_dirty_blocks(), vec![0]);
+        assert_eq!(group.take_dirty_allocations(), vec![0]);
+        for _ in 0..NODES_PER_BLOCK {
+            group.new_file().unwrap();
+        }
+        group.take_dirty_blocks();
+        group.take_dirty_allocations();
+
+        group.get_mut(NODES_PER_BLOCK).unwrap();
+        group.get(2).unwrap();
+
+        assert_eq!(group.take_dirty_blocks(), vec![1]);
+        assert!(group.take_dirty_blocks().is_empty());
+        assert!(group.take_dirty_allocations().is_empty());
+    }
+
+    #[test]
+    fn new_nodes_start_with_group_flags() {
+        let mut group = InodeGroup::new(Bitmap::new(1), 80, EXTENTS_FLAG);
+
+        assert!(group.get(0).unwrap().uses_extents());
+        let file = group.new_file().unwrap();
+        let dir = group.new_dir().unwrap();
+        assert!(group.get(file).unwrap().uses_extents());
+        assert!(group.get(dir).unwrap().uses_extents());
+    }
+
+    #[test]
+    fn removed_inode_number_is_reused() {
+        let mut group = InodeGroup::new(Bitmap::new(1), 80, 0);
+        let file = group.new_file().unwrap();
+        let dir = group.new_dir().unwrap();
+
+        assert!(group.remove(file).is_some());
+
+        assert!(group.get(file).is_none());
+        assert!(group.get(dir).unwrap().is_dir());
+        assert_eq!(group.new_file(), Some(file));
+    }
+
+    #[test]
+    fn allocating_beyond_capacity_returns_none() {
+        let mut group = InodeGroup::new(Bitmap::new(1), NODES_PER_BLOCK, 0);
+        for _ in 1..NODES_PER_BLOCK {
+            assert!(group.new_file().is_some());
+        }
+
+        assert_eq!(group.new_file(), None);
+        assert_eq!(group.new_dir(), None);
+    }
 }
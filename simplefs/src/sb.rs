@@ -1,5 +1,14 @@
 use zerocopy::{AsBytes, FromBytes};
 
+/// Files map their data with extents rather than per-block pointers.
+pub const FEATURE_EXTENTS: u32 = 0x1;
+/// Inodes record 64-bit sizes and second plus nanosecond timestamps. Images without it hold 32-bit
+/// millisecond timestamps and are upgraded when mounted.
+pub const FEATURE_WIDE_INODES: u32 = 0x2;
+/// Directories hold binary entry records. Images without it hold `inum:name` text lines and are
+/// converted when mounted.
+pub const FEATURE_DIR_RECORDS: u32 = 0x4;
+
 /// The first block of the file system storing information critical for mounting
 /// the file system and verifying the underlying disk is formatted correctly.
 ///
@@ -18,14 +27,34 @@ pub struct SuperBlock {
     pub inodes_count: u32,
     /// All the remaining blocks are allocating to storing user data.
     pub blocks_count: u32,
-    /// All blocks currently in use by the filesystem.
+    /// Data blocks held back from regular allocations so the file system never fills completely.
     pub reserved_blocks_count: u32,
     /// All blocks available to be allocated by the system.
     pub free_blocks_count: u32,
     /// The number of remaining available inodes.
     pub free_inodes_count: u32,
-    /// The index of the next available free block.
+    /// A hint for the next available free block, every data block before it is in use. Allocations
+    /// search from here rather than the start of the data region.
     pub free_list: u32,
+    /// The total number of blocks on the device covered by the file system.
+    pub total_blocks_count: u32,
+    /// The first block of the inode table.
+    pub inode_table_start: u32,
+    /// The number of blocks making up the inode table.
+    pub inode_table_blocks: u32,
+    /// The first block of the data region, every block after it is available for user data.
+    pub data_region_start: u32,
+    /// The first block of the data block bitmap.
+    pub data_bitmap_start: u32,
+    /// The number of blocks making up the data block bitmap.
+    pub data_bitmap_blocks: u32,
+    /// The first block of the inode bitmap.
+    pub inode_bitmap_start: u32,
+    /// The number of blocks making up the inode bitmap.
+    pub inode_bitmap_blocks: u32,
+    /// Optional on-disk format features chosen when the file system was formatted, see the
+    /// `FEATURE_*` constants.
+    pub feature_flags: u32,
 }
 
 impl SuperBlock {
@@ -38,6 +67,15 @@ impl SuperBlock {
             free_blocks_count: 0,
             free_inodes_count: 0,
             free_list: 0,
+            total_blocks_count: 0,
+            inode_table_start: 0,
+            inode_table_blocks: 0,
+            data_region_start: 0,
+            data_bitmap_start: 0,
+            data_bitmap_blocks: 0,
+            inode_bitmap_start: 0,
+            inode_bitmap_blocks: 0,
+            feature_flags: 0,
         }
     }
 
@@ -57,6 +95,10 @@ impl SuperBlock {
     pub fn serialize(&self) -> &[u8] {
         self.as_bytes()
     }
+
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.feature_flags & feature != 0
+    }
 }
 
 #[cfg(test)]
@@ -71,6 +113,15 @@ mod tests {
         sb.sb_magic = TEST_MAGIC; // non-zero superblock value.
         sb.inodes_count = 5;
         sb.blocks_count = 56;
+        sb.total_blocks_count = 64;
+        sb.inode_table_start = 3;
+        sb.inode_table_blocks = 5;
+        sb.data_region_start = 8;
+        sb.data_bitmap_start = 1;
+        sb.data_bitmap_blocks = 1;
+        sb.inode_bitmap_start = 2;
+        sb.inode_bitmap_blocks = 1;
+        sb.feature_flags = FEATURE_EXTENTS;
         let encoded = sb.serialize();
 
         let parsed = SuperBlock::parse(encoded, TEST_MAGIC);